@@ -0,0 +1,287 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Instant;
+
+use TreeMap;
+use network::{PuckNetworkResult, TcpConfig};
+use network::heartbeat::Latency;
+use network::codec::AsymmetricCodec;
+use network::client::{ClientEventHandler, ClientInboundEvent, ChannelToServer, run_client};
+use network::PoisonPill;
+use network::conditioner::{Conditions, condition_client};
+use super::{App, AppEvent};
+use super::messages::{ClientMessage, ServerMessage, AppClientMessage, AppServerMessage};
+use super::replication::{Replica, ApplyError};
+use super::prediction::Predictor;
+
+pub const REPLICA_HISTORY : usize = 64; // states kept to apply deltas against
+
+pub type AppClientInboundEvent<A> = ClientInboundEvent<AppServerMessage<A>, AppClientMessage<A>>;
+
+// mirrors the state of an AppServer, nothing is simulated locally
+pub struct AppClient<A> where A : App {
+    pub server_address: SocketAddr,
+    pub tick_rate: Option<u64>, // known once welcomed
+    pub tick: u64,
+    pub previous: TreeMap<A::Id, A::Entity>,
+    pub entities: TreeMap<A::Id, A::Entity>,
+    pub received_at: Option<Instant>, // when entities last changed, for interpolation
+    pub replica: Replica<A::Id, A::Entity>,
+    pub render_events: Vec<A::RenderEvent>, // accumulated until taken
+    pub owned: Vec<A::Id>, // the entities the server accepts our events for
+    pub input_applied: Option<u64>, // latest Input seq the server has acknowledged
+    pub prediction: Predictor<A>, // owned entities run ahead of entities, see predict
+    pub latency: Option<Latency>, // smoothed round trip to the server, once measured
+    pub connected: bool,
+    pub finished: bool,
+    channel_to_server: Option<ChannelToServer<AppClientMessage<A>>>,
+    inbound: Receiver<AppClientInboundEvent<A>>,
+    poison_pill: Option<PoisonPill>, // None when something else drives the events
+}
+
+pub fn connect_app_client<A, C>(server_address: SocketAddr, config: TcpConfig) -> PuckNetworkResult<AppClient<A>> where A : App, C : AsymmetricCodec<AppServerMessage<A>, AppClientMessage<A>> {
+    let (sender, receiver) = channel();
+    let poison_pill = run_client::<AppServerMessage<A>, AppClientMessage<A>, C>(ClientEventHandler { sender }, server_address, A::schema_hash(), config)?;
+
+    Ok(AppClient::from_events(server_address, receiver, Some(poison_pill)))
+}
+
+// as connect_app_client, but everything to and from the server goes through the conditions, which the game can change as it runs
+pub fn connect_conditioned_app_client<A, C>(server_address: SocketAddr, config: TcpConfig, conditions: Conditions) -> PuckNetworkResult<AppClient<A>> where A : App, C : AsymmetricCodec<AppServerMessage<A>, AppClientMessage<A>> + AsymmetricCodec<AppClientMessage<A>, AppServerMessage<A>> {
+    let (sender, receiver) = channel();
+    let handler = condition_client::<AppServerMessage<A>, AppClientMessage<A>, C>(ClientEventHandler { sender }, conditions);
+    let poison_pill = run_client::<AppServerMessage<A>, AppClientMessage<A>, C>(handler, server_address, A::schema_hash(), config)?;
+
+    Ok(AppClient::from_events(server_address, receiver, Some(poison_pill)))
+}
+
+impl<A> AppClient<A> where A : App {
+    // for transports other than run_client, e.g. a Loopback fed by the receiver's sender
+    pub fn from_events(server_address: SocketAddr, inbound: Receiver<AppClientInboundEvent<A>>, poison_pill: Option<PoisonPill>) -> AppClient<A> {
+        AppClient {
+            server_address,
+            tick_rate: None,
+            tick: 0,
+            previous: TreeMap::new(),
+            entities: TreeMap::new(),
+            received_at: None,
+            replica: Replica::new(REPLICA_HISTORY),
+            render_events: Vec::new(),
+            owned: Vec::new(),
+            input_applied: None,
+            prediction: Predictor::new(60),
+            latency: None,
+            connected: false,
+            finished: false,
+            channel_to_server: None,
+            inbound,
+            poison_pill,
+        }
+    }
+
+    // returns how many states arrived
+    pub fn poll(&mut self) -> usize {
+        let mut states = 0;
+        loop {
+            match self.inbound.try_recv() {
+                Ok(event) => {
+                    if self.handle(event) {
+                        states += 1;
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                },
+            }
+        }
+        states
+    }
+
+    fn handle(&mut self, event: AppClientInboundEvent<A>) -> bool {
+        match event {
+            ClientInboundEvent::ServerConnected { channel_to_server, .. } => {
+                self.channel_to_server = Some(channel_to_server);
+                self.connected = true;
+                false
+            },
+            ClientInboundEvent::ServerMessage { event, .. } => self.handle_message(event),
+            ClientInboundEvent::ServerLatency { latency, .. } => {
+                self.latency = Some(latency);
+                false
+            },
+            ClientInboundEvent::ServerDisconnected { .. } | ClientInboundEvent::Reconnecting { .. } => {
+                // channel_to_server buffers until we're reconnected
+                self.connected = false;
+                false
+            },
+            ClientInboundEvent::Reconnected { .. } => {
+                // the server sees a new client, it'll send ownership and a full state again
+                self.reset();
+                self.connected = true;
+                false
+            },
+            ClientInboundEvent::HandshakeRejected { local, remote, .. } => {
+                println!("AppClient :: server rejected our handshake {:?}, it expected {:?}", local, remote);
+                self.channel_to_server = None;
+                self.connected = false;
+                self.finished = true;
+                false
+            },
+            ClientInboundEvent::FailedToConnect { .. } | ClientInboundEvent::ClientFinished { .. } => {
+                self.channel_to_server = None;
+                self.connected = false;
+                self.finished = true;
+                false
+            },
+        }
+    }
+
+    fn handle_message(&mut self, message: AppServerMessage<A>) -> bool {
+        match message {
+            ServerMessage::Welcome { tick_rate, tick } => {
+                self.reset();
+                self.tick_rate = Some(tick_rate);
+                self.tick = tick;
+                self.prediction.tick_rate = tick_rate;
+                false
+            },
+            ServerMessage::State(delta) => {
+                match self.replica.apply(delta) {
+                    Ok(tick) => {
+                        let entities = self.replica.latest().map(|(_, entities)| entities.clone()).unwrap_or_default();
+                        self.previous = ::std::mem::replace(&mut self.entities, entities);
+                        self.tick = tick;
+                        self.received_at = Some(Instant::now());
+                        self.prediction.reconcile(tick, &self.entities, self.input_applied, &self.owned);
+                        self.send_message(ClientMessage::Ack { tick });
+                        true
+                    },
+                    Err(ApplyError::MissingBaseline(_)) => {
+                        self.send_message(ClientMessage::Resync);
+                        false
+                    },
+                    Err(ApplyError::Stale) => false,
+                }
+            },
+            ServerMessage::RenderEvents { mut events, .. } => {
+                self.render_events.append(&mut events);
+                false
+            },
+            ServerMessage::Ownership { owned } => {
+                self.owned = owned;
+                false
+            },
+            ServerMessage::InputApplied { seq } => {
+                self.input_applied = Some(seq);
+                false
+            },
+        }
+    }
+
+    // forget everything tied to the last connection, a restarted server counts ticks and input seqs from scratch
+    // entities stay as they are until the new full state replaces them
+    fn reset(&mut self) {
+        self.replica = Replica::new(REPLICA_HISTORY);
+        self.prediction = Predictor::new(self.prediction.tick_rate);
+        self.owned = Vec::new();
+        self.input_applied = None;
+    }
+
+    // false if there's no connection to send on
+    pub fn send(&mut self, events: Vec<AppEvent<A>>) -> bool {
+        if events.is_empty() {
+            return true;
+        }
+        self.send_message(ClientMessage::Events(events))
+    }
+
+    // call once per local tick, even without events, the input takes effect on prediction.predicted immediately
+    pub fn predict(&mut self, events: Vec<AppEvent<A>>) -> bool {
+        let seq = self.prediction.input(&self.owned, events.clone());
+        self.send_message(ClientMessage::Input { seq, events })
+    }
+
+    pub fn send_message(&mut self, message: AppClientMessage<A>) -> bool {
+        match self.channel_to_server {
+            Some(ref channel) => channel.sender.unbounded_send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    // ask for a full state on the next tick
+    pub fn resync(&mut self) -> bool {
+        self.send_message(ClientMessage::Resync)
+    }
+
+    pub fn take_render_events(&mut self) -> Vec<A::RenderEvent> {
+        self.render_events.split_off(0)
+    }
+
+    // how far between the previous and current state we should be rendering, 0 to 1
+    pub fn alpha(&self) -> f64 {
+        match (self.tick_rate, self.received_at) {
+            (Some(tick_rate), Some(received_at)) => {
+                let elapsed = received_at.elapsed();
+                let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+                (secs * tick_rate as f64).min(1.0)
+            },
+            _ => 1.0,
+        }
+    }
+
+    pub fn shutdown(self) {
+        if let Some(poison_pill) = self.poison_pill {
+            let _ = poison_pill.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use network::queue::QueueMetrics;
+    use app::replication::full;
+    use app::test_app::{TestApp, TestId, Counter};
+    use futures::sync::mpsc::unbounded;
+
+    fn state(value: i64) -> TreeMap<TestId, Counter> {
+        let mut entities = TreeMap::new();
+        entities.insert(TestId::Counter(0), Counter { value, x: 0.0, ttl: 0 });
+        entities
+    }
+
+    fn message(message: AppServerMessage<TestApp>) -> AppClientInboundEvent<TestApp> {
+        ClientInboundEvent::ServerMessage { address: "127.0.0.1:1".parse().unwrap(), event: message }
+    }
+
+    #[test]
+    fn a_restarted_server_is_followed_from_its_first_tick() {
+        let address = "127.0.0.1:1".parse().unwrap();
+        let (sender, receiver) = channel();
+        let (to_server, _from_client) = unbounded();
+        let mut client : AppClient<TestApp> = AppClient::from_events(address, receiver, None);
+
+        sender.send(ClientInboundEvent::ServerConnected { address, channel_to_server: ChannelToServer { sender: to_server, queue: QueueMetrics::new() } }).unwrap();
+        sender.send(message(ServerMessage::Welcome { tick_rate: 60, tick: 500 })).unwrap();
+        sender.send(message(ServerMessage::Ownership { owned: vec![TestId::Counter(0)] })).unwrap();
+        sender.send(message(ServerMessage::InputApplied { seq: 40 })).unwrap();
+        sender.send(message(ServerMessage::State(full(500, &state(1))))).unwrap();
+        assert_eq!(client.poll(), 1);
+        assert_eq!(client.entities, state(1));
+
+        sender.send(ClientInboundEvent::Reconnected { address }).unwrap();
+        sender.send(message(ServerMessage::Welcome { tick_rate: 60, tick: 0 })).unwrap();
+        assert_eq!(client.poll(), 0);
+        assert!(client.owned.is_empty() && client.input_applied.is_none());
+        assert!(client.replica.latest().is_none());
+        assert_eq!(client.prediction.next_seq, 1);
+        assert_eq!(client.entities, state(1));
+
+        sender.send(message(ServerMessage::State(full(1, &state(2))))).unwrap();
+        assert_eq!(client.poll(), 1);
+        assert_eq!(client.tick, 1);
+        assert_eq!(client.entities, state(2));
+    }
+}
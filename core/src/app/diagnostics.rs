@@ -0,0 +1,59 @@
+use event::TimerId;
+
+// something the simulation did that's probably a bug in the App, reported rather than silently dropped
+// origin is the entity whose sink produced the event, None for injected events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Diagnostic<Id> {
+    UndeliveredEvent { tick: u64, target: Id, origin: Option<Id> }, // EntityEvent (or a timer) for an entity that doesn't exist
+    OverwrittenSpawn { tick: u64, id: Id, origin: Option<Id> },
+    UnknownDelete { tick: u64, id: Id, origin: Option<Id> },
+    DuplicateTimer { tick: u64, timer_id: TimerId, origin: Option<Id> }, // the id was already live, the new timer is dropped
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DiagnosticsMode {
+    Ignore,
+    Warn, // println
+    PanicInDebug, // panics with debug assertions, warns otherwise
+    Collect, // kept until drained, for tests
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostics<Id> {
+    pub mode: DiagnosticsMode,
+    pub collected: Vec<Diagnostic<Id>>,
+}
+
+impl<Id> Default for Diagnostics<Id> where Id : ::std::fmt::Debug {
+    fn default() -> Diagnostics<Id> {
+        Diagnostics::new(DiagnosticsMode::Warn)
+    }
+}
+
+impl<Id> Diagnostics<Id> where Id : ::std::fmt::Debug {
+    pub fn new(mode: DiagnosticsMode) -> Diagnostics<Id> {
+        Diagnostics {
+            mode,
+            collected: Vec::new(),
+        }
+    }
+
+    pub fn report(&mut self, diagnostic: Diagnostic<Id>) {
+        match self.mode {
+            DiagnosticsMode::Ignore => (),
+            DiagnosticsMode::Warn => println!("Simulation :: {:?}", diagnostic),
+            DiagnosticsMode::PanicInDebug => {
+                if cfg!(debug_assertions) {
+                    panic!("Simulation :: {:?}", diagnostic);
+                } else {
+                    println!("Simulation :: {:?}", diagnostic);
+                }
+            },
+            DiagnosticsMode::Collect => self.collected.push(diagnostic),
+        }
+    }
+
+    pub fn drain(&mut self) -> Vec<Diagnostic<Id>> {
+        self.collected.split_off(0)
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use TreeMap;
+use event::TimerId;
+use super::App;
+
+// per kind counters, owned by the simulation and saved with its snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdAllocator<K> where K : Ord {
+    pub next: TreeMap<K, u64>, // lowest number of each kind that may still be handed out
+    pub next_timer: u64, // timers are counted like another kind
+}
+
+impl<K> IdAllocator<K> where K : Ord + Clone {
+    pub fn new() -> IdAllocator<K> {
+        IdAllocator {
+            next: TreeMap::new(),
+            next_timer: 0,
+        }
+    }
+
+    // immediate allocation, for use outside of a step (building initial worlds, servers spawning players)
+    pub fn allocate(&mut self, kind: K) -> u64 {
+        let counter = self.next.entry(kind).or_insert(0);
+        let n = *counter;
+        *counter += 1;
+        n
+    }
+
+    pub fn allocate_timer(&mut self) -> TimerId {
+        let n = self.next_timer;
+        self.next_timer += 1;
+        TimerId(n)
+    }
+
+    // an id of this kind and number exists, make sure it's never handed out again
+    pub fn observe(&mut self, kind: K, n: u64) {
+        let counter = self.next.entry(kind).or_insert(0);
+        if n >= *counter {
+            *counter = n + 1;
+        }
+    }
+
+    pub fn observe_id<A>(&mut self, id: &A::Id) where A : App<IdKind=K> {
+        if let Some((kind, n)) = A::id_kind(id) {
+            self.observe(kind, n);
+        }
+    }
+
+    // one lease per entity simulated this step, lease i hands out base + k * stride + i
+    // so leases never overlap and the ids don't depend on the order the leases were used in
+    pub fn leases(&self, count: usize) -> Vec<IdLease<K>> {
+        let bases = Arc::new(self.next.clone());
+        let stride = count as u64;
+        (0..count).map(|i| IdLease {
+            bases: bases.clone(),
+            timer_base: self.next_timer,
+            ordinal: i as u64,
+            stride,
+            taken: TreeMap::new(),
+            timers_taken: 0,
+        }).collect()
+    }
+
+    // advance every counter past what the leases could have handed out
+    pub fn commit(&mut self, stride: usize, taken: &TreeMap<K, u64>, timers_taken: u64) {
+        for (kind, count) in taken {
+            let counter = self.next.entry(kind.clone()).or_insert(0);
+            *counter += *count * stride as u64;
+        }
+        self.next_timer += timers_taken * stride as u64;
+    }
+}
+
+impl<K> Default for IdAllocator<K> where K : Ord + Clone {
+    fn default() -> IdAllocator<K> {
+        IdAllocator::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdLease<K> where K : Ord {
+    bases: Arc<TreeMap<K, u64>>,
+    timer_base: u64,
+    ordinal: u64,
+    stride: u64,
+    pub taken: TreeMap<K, u64>, // how many of each kind this lease has handed out
+    pub timers_taken: u64,
+}
+
+impl<K> IdLease<K> where K : Ord + Clone {
+    // a lease backed by nothing, for sinks that live outside of a simulation step
+    pub fn detached() -> IdLease<K> {
+        IdLease {
+            bases: Arc::new(TreeMap::new()),
+            timer_base: 0,
+            ordinal: 0,
+            stride: 1,
+            taken: TreeMap::new(),
+            timers_taken: 0,
+        }
+    }
+
+    pub fn reserve(&mut self, kind: K) -> u64 {
+        let base = self.bases.get(&kind).cloned().unwrap_or(0);
+        let k = self.taken.entry(kind).or_insert(0);
+        let n = base + *k * self.stride + self.ordinal;
+        *k += 1;
+        n
+    }
+
+    pub fn reserve_timer(&mut self) -> TimerId {
+        let n = self.timer_base + self.timers_taken * self.stride + self.ordinal;
+        self.timers_taken += 1;
+        TimerId(n)
+    }
+
+    // reserve a typed id, e.g. sink.ids.next::<Self>(IdKind::Shot)
+    pub fn next<A>(&mut self, kind: K) -> A::Id where A : App<IdKind=K> {
+        let n = self.reserve(kind.clone());
+        A::id_for(kind, n)
+    }
+}
+
+// the per kind maximum of what each lease took, the amount every counter must advance by (in strides)
+pub fn max_taken<K>(a: &mut TreeMap<K, u64>, b: &TreeMap<K, u64>) where K : Ord + Clone {
+    for (kind, count) in b {
+        let current = a.entry(kind.clone()).or_insert(0);
+        if *count > *current {
+            *current = *count;
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use cgmath::{Vector2, Vector3};
+
+use TreeMap;
+
+// blends two simulation states for rendering between ticks, alpha 0 is previous and 1 is current
+pub trait Interpolate {
+    fn interpolate(previous: &Self, current: &Self, alpha: f64) -> Self;
+}
+
+impl Interpolate for f64 {
+    fn interpolate(previous: &f64, current: &f64, alpha: f64) -> f64 {
+        previous + (current - previous) * alpha
+    }
+}
+
+impl Interpolate for f32 {
+    fn interpolate(previous: &f32, current: &f32, alpha: f64) -> f32 {
+        previous + (current - previous) * alpha as f32
+    }
+}
+
+impl Interpolate for Vector2<f64> {
+    fn interpolate(previous: &Vector2<f64>, current: &Vector2<f64>, alpha: f64) -> Vector2<f64> {
+        previous + (current - previous) * alpha
+    }
+}
+
+impl Interpolate for Vector2<f32> {
+    fn interpolate(previous: &Vector2<f32>, current: &Vector2<f32>, alpha: f64) -> Vector2<f32> {
+        previous + (current - previous) * alpha as f32
+    }
+}
+
+impl Interpolate for Vector3<f64> {
+    fn interpolate(previous: &Vector3<f64>, current: &Vector3<f64>, alpha: f64) -> Vector3<f64> {
+        previous + (current - previous) * alpha
+    }
+}
+
+impl Interpolate for Vector3<f32> {
+    fn interpolate(previous: &Vector3<f32>, current: &Vector3<f32>, alpha: f64) -> Vector3<f32> {
+        previous + (current - previous) * alpha as f32
+    }
+}
+
+// entities in both states are blended, ones that only exist in current are used as is
+// entities that only exist in previous were deleted and are dropped
+pub fn interpolate_entities<Id, Entity>(previous: &TreeMap<Id, Entity>, current: &TreeMap<Id, Entity>, alpha: f64) -> TreeMap<Id, Entity> where Id : Ord + Clone, Entity : Interpolate + Clone {
+    current.iter().map(|(id, entity)| {
+        let blended = match previous.get(id) {
+            Some(before) => Entity::interpolate(before, entity, alpha),
+            None => entity.clone(),
+        };
+        (id.clone(), blended)
+    }).collect()
+}
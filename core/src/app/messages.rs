@@ -0,0 +1,35 @@
+use event::Event;
+use super::App;
+use super::replication::Delta;
+
+// client -> server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage<Id, Entity, EntityEvent, RenderEvent> {
+    Events(Vec<Event<Id, Entity, EntityEvent, RenderEvent>>), // client originated, e.g. from handle_input
+    Input { seq: u64, events: Vec<Event<Id, Entity, EntityEvent, RenderEvent>> }, // as Events, but acknowledged so it can be predicted
+    Ack { tick: u64 }, // the client holds the state after tick, future deltas may be against it
+    Resync, // the client lost track, send a full state
+}
+
+// server -> client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage<Id, Entity, RenderEvent> {
+    Welcome { tick_rate: u64, tick: u64 },
+    State(Delta<Id, Entity>), // the authoritative state after delta.tick
+    RenderEvents { tick: u64, events: Vec<RenderEvent> },
+    Ownership { owned: Vec<Id> }, // every entity the client is now the authority for, sent whenever it changes
+    InputApplied { seq: u64 }, // the latest Input included in the State that follows
+}
+
+pub type PeerId = u32;
+
+// peer <-> peer, for rollback sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PeerMessage<Id, Entity, EntityEvent, RenderEvent> {
+    Input { peer: PeerId, tick: u64, events: Vec<Event<Id, Entity, EntityEvent, RenderEvent>> }, // sent once for every tick, even when empty
+    Checksum { peer: PeerId, tick: u64, checksum: u64 }, // of the state after tick, once tick's input is confirmed
+}
+
+pub type AppClientMessage<A> = ClientMessage<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent>;
+pub type AppServerMessage<A> = ServerMessage<<A as App>::Id, <A as App>::Entity, <A as App>::RenderEvent>;
+pub type AppPeerMessage<A> = PeerMessage<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent>;
@@ -1,4 +1,21 @@
 pub mod runner;
+pub mod replay;
+pub mod snapshot;
+pub mod ids;
+pub mod timers;
+pub mod diagnostics;
+pub mod spatial;
+pub mod interpolate;
+pub mod messages;
+pub mod replication;
+pub mod ownership;
+pub mod prediction;
+pub mod rollback;
+pub mod server;
+pub mod client;
+
+#[cfg(test)]
+pub mod test_app;
 
 use std::fmt::Debug;
 
@@ -12,23 +29,78 @@ use serde::de::DeserializeOwned;
 
 use event::*;
 
+pub use self::runner::{Simulation, StepMode};
+pub use self::ids::{IdAllocator, IdLease};
+pub use self::timers::TimerQueue;
+pub use self::diagnostics::{Diagnostic, Diagnostics, DiagnosticsMode};
+pub use self::spatial::{Bounds, SpatialGrid, World};
+pub use self::interpolate::{Interpolate, interpolate_entities};
+pub use self::ownership::{Owner, Ownership, OwnershipError};
+pub use self::prediction::{Predictor, Correction};
+pub use self::rollback::{RollbackSession, InputPrediction, Desync};
+pub use self::server::{AppServer, run_app_server};
+pub use self::client::{AppClient, connect_app_client};
+use self::snapshot::{SchemaVersion, SnapshotHeader, SnapshotFormat, SnapshotResult, SnapshotError, AppSnapshotBody};
+
 pub type TreeMap<K, V> = BTreeMap<K, V>;
 
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SimSettings {
     pub tick_rate: u64,
 }
 
-pub type IdSeed = u64;
+pub type AppEvent<A> = Event<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent>;
 
 pub trait App {
-    type Id : Clone + Hash + Debug + Eq + Ord + Serialize + DeserializeOwned;
-    type Entity : Clone + Debug + Serialize + DeserializeOwned; // do we need Eq?
-    type EntityEvent : Clone + Debug + Serialize + DeserializeOwned;
-    type RenderEvent : Clone + Debug + Serialize + DeserializeOwned;
-
-    fn modify_id(id: &Self::Id, seed: IdSeed) -> Option<Self::Id>;
-    fn handle_entity_event(event:&Self::EntityEvent, id: &Self::Id, entity: &mut Self::Entity, sink: &mut Sink<Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>>);
-    fn simulate(time:Tick, entities:&TreeMap<Self::Id, Self::Entity>, id: &Self::Id, entity: &Self::Entity, sink: &mut CombinedSink<Self::EntityEvent, Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>>);
-}
\ No newline at end of file
+    type Id : Clone + Hash + Debug + Eq + Ord + Serialize + DeserializeOwned + Send + Sync + 'static;
+    type Entity : Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static; // do we need Eq?
+    type EntityEvent : Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static;
+    type RenderEvent : Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static;
+    type IdKind : Clone + Debug + Eq + Ord + Serialize + DeserializeOwned + Send + Sync; // the families of ids the allocator counts separately
+
+    fn id_for(kind: Self::IdKind, n: u64) -> Self::Id;
+    fn id_kind(id: &Self::Id) -> Option<(Self::IdKind, u64)>; // None for singleton ids that are never allocated
+    fn handle_entity_event(event:&Self::EntityEvent, id: &Self::Id, entity: &mut Self::Entity, sink: &mut Sink<AppEvent<Self>, Self::IdKind>);
+    fn simulate(time:Tick, entities:&World<Self::Id, Self::Entity>, id: &Self::Id, entity: &Self::Entity, sink: &mut CombinedSink<Self::EntityEvent, AppEvent<Self>, Self::IdKind>);
+
+    // entities located here can be found with entities.within_radius/within_rect inside simulate
+    fn locate(entity: &Self::Entity) -> Option<Bounds> {
+        let _ = entity;
+        None
+    }
+
+    // should be around the size of the largest commonly queried radius
+    fn spatial_cell_size() -> f64 {
+        64.0
+    }
+
+    // interest management, what a client viewing from viewer (usually its player) is sent by an AppServer
+    // entities entering or leaving view arrive at the client as spawns and deletes
+    fn entity_visible(viewer: &Self::Id, entities: &TreeMap<Self::Id, Self::Entity>, id: &Self::Id, entity: &Self::Entity) -> bool {
+        let _ = (viewer, entities, id, entity);
+        true
+    }
+
+    fn render_event_visible(viewer: &Self::Id, entities: &TreeMap<Self::Id, Self::Entity>, event: &Self::RenderEvent) -> bool {
+        let _ = (viewer, entities, event);
+        true
+    }
+
+    // sent in the connection handshake, clients and servers with a different hash are rejected before any messages
+    fn schema_hash() -> u64 {
+        Self::schema_version() as u64
+    }
+
+    // bump this whenever Entity/EntityEvent change shape, it's written into every snapshot header
+    fn schema_version() -> SchemaVersion {
+        0
+    }
+
+    // called when loading a snapshot saved with a different schema version
+    // decode the body with format.decode into the old types and convert
+    fn migrate_snapshot(header: &SnapshotHeader, format: SnapshotFormat, body: &[u8]) -> SnapshotResult<AppSnapshotBody<Self>> {
+        let _ = (format, body);
+        Err(SnapshotError::UnsupportedSchemaVersion { found: header.schema_version, expected: Self::schema_version() })
+    }
+}
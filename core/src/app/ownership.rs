@@ -0,0 +1,126 @@
+use TreeMap;
+use event::Event;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Owner<K> {
+    Server, // the default, anything not explicitly handed to a client
+    Client(K),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnershipError<Id> {
+    NotOwner(Id), // targets an entity owned by the server or another client
+    ServerOnly, // only entity and render events are accepted from clients, spawns, deletes and timers are the server's
+}
+
+// who's the authority for each entity, clients may only send events targeting entities they own
+pub struct Ownership<Id, K> where Id : Ord {
+    owners: TreeMap<Id, K>,
+}
+
+impl<Id, K> Default for Ownership<Id, K> where Id : Ord + Clone, K : Clone + Eq {
+    fn default() -> Ownership<Id, K> {
+        Ownership::new()
+    }
+}
+
+impl<Id, K> Ownership<Id, K> where Id : Ord + Clone, K : Clone + Eq {
+    pub fn new() -> Ownership<Id, K> {
+        Ownership {
+            owners: TreeMap::new(),
+        }
+    }
+
+    pub fn assign(&mut self, id: Id, client: K) {
+        self.owners.insert(id, client);
+    }
+
+    // hands the entity back to the server
+    pub fn release(&mut self, id: &Id) {
+        self.owners.remove(id);
+    }
+
+    pub fn owner(&self, id: &Id) -> Owner<K> {
+        match self.owners.get(id) {
+            Some(client) => Owner::Client(client.clone()),
+            None => Owner::Server,
+        }
+    }
+
+    pub fn owns(&self, client: &K, id: &Id) -> bool {
+        self.owners.get(id).map(|c| c == client).unwrap_or(false)
+    }
+
+    pub fn owned_by(&self, client: &K) -> Vec<Id> {
+        self.owners.iter().filter(|&(_, c)| c == client).map(|(id, _)| id.clone()).collect()
+    }
+
+    // everything the client owned reverts to the server
+    pub fn remove_client(&mut self, client: &K) -> Vec<Id> {
+        let owned = self.owned_by(client);
+        for id in &owned {
+            self.owners.remove(id);
+        }
+        owned
+    }
+
+    // forget owners of entities that no longer exist
+    pub fn retain_existing<Entity>(&mut self, entities: &TreeMap<Id, Entity>) {
+        let gone : Vec<Id> = self.owners.keys().filter(|id| !entities.contains_key(id)).cloned().collect();
+        for id in gone {
+            self.owners.remove(&id);
+        }
+    }
+
+    // timer ids are global, a client choosing one could replace or cancel anyone's timer, render events target nothing
+    pub fn validate<Entity, EntityEvent, RenderEvent>(&self, client: &K, event: &Event<Id, Entity, EntityEvent, RenderEvent>) -> Result<(), OwnershipError<Id>> {
+        let target = match *event {
+            Event::EntityEvent(ref id, _) => id,
+            Event::RenderEvent(_) => return Ok(()),
+            Event::Shutdown | Event::SpawnEvent(_, _) | Event::Delete(_) | Event::DeleteRange(_, _) | Event::ScheduleEvent(_, _, _, _) | Event::CancelEvent(_) => return Err(OwnershipError::ServerOnly),
+        };
+        if self.owns(client, target) {
+            Ok(())
+        } else {
+            Err(OwnershipError::NotOwner(target.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::{TimerId, Delivery};
+
+    type TestEvent = Event<u32, (), (), ()>;
+
+    #[test]
+    fn clients_only_send_events_to_what_they_own() {
+        let mut ownership : Ownership<u32, u8> = Ownership::new();
+        ownership.assign(1, 7);
+        ownership.assign(2, 8);
+
+        assert_eq!(ownership.validate(&7, &TestEvent::EntityEvent(1, ())), Ok(()));
+        assert_eq!(ownership.validate(&7, &TestEvent::EntityEvent(2, ())), Err(OwnershipError::NotOwner(2)));
+        assert_eq!(ownership.validate(&7, &TestEvent::EntityEvent(3, ())), Err(OwnershipError::NotOwner(3)));
+        assert_eq!(ownership.validate(&7, &TestEvent::RenderEvent(())), Ok(()));
+    }
+
+    #[test]
+    fn spawns_deletes_and_timers_are_server_only() {
+        let mut ownership : Ownership<u32, u8> = Ownership::new();
+        ownership.assign(1, 7);
+
+        let refused = vec![
+            TestEvent::Shutdown,
+            TestEvent::SpawnEvent(1, ()),
+            TestEvent::Delete(1),
+            TestEvent::DeleteRange(1, 1),
+            TestEvent::ScheduleEvent(TimerId(1), Delivery::InTicks(1), 1, ()),
+            TestEvent::CancelEvent(TimerId(1)),
+        ];
+        for event in refused {
+            assert_eq!(ownership.validate(&7, &event), Err(OwnershipError::ServerOnly));
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+use {Tick, TreeMap, SimRng};
+use event::*;
+use super::{App, AppEvent};
+use super::ids::IdLease;
+use super::runner::NANOSECONDS_IN_A_SECOND;
+use super::interpolate::Interpolate;
+use super::spatial::{SpatialGrid, World};
+use super::replication::entity_hash;
+
+pub const MAX_UNACKED_INPUTS : usize = 256; // ticks of input, older inputs are assumed lost
+
+// how far a corrected entity is still being shown from its mispredicted state, 1 is entirely
+#[derive(Debug, Clone)]
+pub struct Correction<Entity> {
+    pub from: Entity,
+    pub weight: f64,
+}
+
+// runs the locally owned entities ahead of the server so input takes effect immediately
+// every input is kept until the server says it has applied it, when authoritative state arrives
+// we rewind to it and replay whatever's still unacknowledged
+pub struct Predictor<A> where A : App {
+    pub tick_rate: u64,
+    pub tick: u64, // the tick predicted will be simulated as next
+    pub next_seq: u64,
+    pub unacked: VecDeque<(u64, Vec<AppEvent<A>>)>, // by seq, one entry per predicted tick
+    pub predicted: TreeMap<A::Id, A::Entity>, // the authoritative state with owned entities run ahead
+    pub corrections: TreeMap<A::Id, Correction<A::Entity>>,
+}
+
+impl<A> Predictor<A> where A : App {
+    pub fn new(tick_rate: u64) -> Predictor<A> {
+        Predictor {
+            tick_rate,
+            tick: 0,
+            next_seq: 1,
+            unacked: VecDeque::new(),
+            predicted: TreeMap::new(),
+            corrections: TreeMap::new(),
+        }
+    }
+
+    // records the input for one local tick and predicts its effect, returns the seq to send it with
+    pub fn input(&mut self, owned: &[A::Id], events: Vec<AppEvent<A>>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.predict_step(owned, &events);
+        self.unacked.push_back((seq, events));
+        while self.unacked.len() > MAX_UNACKED_INPUTS {
+            self.unacked.pop_front();
+        }
+        seq
+    }
+
+    // rewind to the authoritative state after tick and replay inputs the server hasn't applied yet
+    // owned entities that end up somewhere other than predicted get a correction to smooth out
+    pub fn reconcile(&mut self, tick: u64, authoritative: &TreeMap<A::Id, A::Entity>, applied: Option<u64>, owned: &[A::Id]) {
+        if let Some(applied) = applied {
+            while self.unacked.front().map(|&(seq, _)| seq <= applied).unwrap_or(false) {
+                self.unacked.pop_front();
+            }
+        }
+
+        let before : Vec<(A::Id, A::Entity)> = owned.iter().filter_map(|id| self.predicted.get(id).map(|e| (id.clone(), e.clone()))).collect();
+
+        self.predicted = authoritative.clone();
+        self.tick = tick;
+        let unacked : Vec<Vec<AppEvent<A>>> = self.unacked.iter().map(|(_, events)| events.clone()).collect();
+        for events in &unacked {
+            self.predict_step(owned, events);
+        }
+
+        for (id, was) in before {
+            if let Some(now) = self.predicted.get(&id) {
+                if entity_hash(&was) != entity_hash(now) {
+                    self.corrections.insert(id, Correction { from: was, weight: 1.0 });
+                }
+            }
+        }
+    }
+
+    // fades corrections out, call once per rendered frame with e.g. 0.85
+    pub fn decay(&mut self, factor: f64) {
+        let mut done = Vec::new();
+        for (id, correction) in self.corrections.iter_mut() {
+            correction.weight *= factor;
+            if correction.weight < 0.01 {
+                done.push(id.clone());
+            }
+        }
+        for id in done {
+            self.corrections.remove(&id);
+        }
+    }
+
+    // what to show, the predicted state with corrections still being blended away
+    pub fn smoothed(&self) -> TreeMap<A::Id, A::Entity> where A::Entity : Interpolate {
+        let mut entities = self.predicted.clone();
+        for (id, correction) in &self.corrections {
+            if let Some(e) = entities.get_mut(id) {
+                *e = A::Entity::interpolate(&correction.from, e, 1.0 - correction.weight);
+            }
+        }
+        entities
+    }
+
+    // the same handle/simulate/self effects sequence the Simulation uses, but only for owned entities
+    // anything routed (spawns, events for others, render events) is left to the server, ids taken for spawns are discarded with them
+    // each entity's rng is forked from the tick and its id, so replaying an input gives the same result every time
+    fn predict_step(&mut self, owned: &[A::Id], events: &[AppEvent<A>]) {
+        let per_tick_ns = NANOSECONDS_IN_A_SECOND / self.tick_rate;
+        let time = Tick {
+            n: self.tick,
+            tick_duration: (per_tick_ns as f64) / (NANOSECONDS_IN_A_SECOND as f64),
+            tick_rate: self.tick_rate,
+        };
+        let tick_rng = SimRng::seeded(self.tick);
+
+        let stepped : Vec<(A::Id, A::Entity)> = {
+            let index = SpatialGrid::build(A::spatial_cell_size(), &self.predicted, A::locate);
+            let world = World { entities: &self.predicted, index: &index };
+
+            owned.iter().filter_map(|id| world.get(id).map(|e| {
+                let mut entity = e.clone();
+                let mut rng = tick_rng.fork(entity_hash(id));
+                let mut lease = IdLease::detached();
+                for ev in events {
+                    if let Event::EntityEvent(ref target, ref entity_event) = *ev {
+                        if target == id {
+                            let mut sink = Sink::with(rng, lease);
+                            A::handle_entity_event(entity_event, id, &mut entity, &mut sink);
+                            rng = sink.rng;
+                            lease = sink.ids;
+                        }
+                    }
+                }
+
+                let mut combined_sink = CombinedSink::with(rng, lease);
+                A::simulate(time, &world, id, &entity, &mut combined_sink);
+                rng = combined_sink.rng;
+                lease = combined_sink.ids;
+                for event in &combined_sink.mine.events {
+                    let mut sink = Sink::with(rng, lease);
+                    A::handle_entity_event(event, id, &mut entity, &mut sink);
+                    rng = sink.rng;
+                    lease = sink.ids;
+                }
+                (id.clone(), entity)
+            })).collect()
+        };
+
+        for (id, entity) in stepped {
+            self.predicted.insert(id, entity);
+        }
+        self.tick += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::test_app::{TestApp, TestId, TestEvent, Counter};
+
+    fn authoritative(value: i64) -> TreeMap<TestId, Counter> {
+        let mut entities = TreeMap::new();
+        entities.insert(TestId::Counter(0), Counter { value, x: 0.0, ttl: 0 });
+        entities.insert(TestId::Counter(1), Counter { value: -value, x: 100.0, ttl: 0 });
+        entities
+    }
+
+    fn add(n: i64) -> Vec<AppEvent<TestApp>> {
+        vec![Event::EntityEvent(TestId::Counter(0), TestEvent::Add(n)), Event::EntityEvent(TestId::Counter(1), TestEvent::Add(n))]
+    }
+
+    #[test]
+    fn reconcile_replays_only_unapplied_inputs() {
+        let owned = [TestId::Counter(0)];
+        let mut predictor : Predictor<TestApp> = Predictor::new(60);
+        predictor.reconcile(0, &authoritative(0), None, &owned);
+        assert_eq!(predictor.input(&owned, add(5)), 1);
+        assert_eq!(predictor.input(&owned, add(7)), 2);
+        assert_eq!(predictor.tick, 2);
+
+        // the server applied the first input, the second is replayed on top of its state
+        predictor.reconcile(10, &authoritative(100), Some(1), &owned);
+        assert_eq!(predictor.unacked.len(), 1);
+        assert_eq!(predictor.tick, 11);
+
+        let mut expected : Predictor<TestApp> = Predictor::new(60);
+        expected.reconcile(10, &authoritative(100), None, &owned);
+        expected.input(&owned, add(7));
+        assert_eq!(predictor.predicted, expected.predicted);
+        assert!(predictor.predicted[&TestId::Counter(0)].value > 100);
+
+        // entities owned by others are never predicted, even with events for them in the input
+        assert_eq!(predictor.predicted[&TestId::Counter(1)], authoritative(100)[&TestId::Counter(1)]);
+
+        predictor.reconcile(11, &authoritative(200), Some(2), &owned);
+        assert!(predictor.unacked.is_empty());
+        assert_eq!(predictor.predicted, authoritative(200));
+    }
+
+    #[test]
+    fn rng_driven_entities_replay_to_the_same_prediction() {
+        let owned = [TestId::Counter(0)];
+        let mut predictor : Predictor<TestApp> = Predictor::new(60);
+        predictor.reconcile(0, &authoritative(0), None, &owned);
+        let mut values = Vec::new();
+        for _ in 0..20 {
+            predictor.input(&owned, Vec::new());
+            values.push(predictor.predicted[&TestId::Counter(0)].value);
+        }
+        // the drift comes from the rng, it isn't the same every tick
+        assert!(values.windows(2).any(|w| w[1] - w[0] != values[1] - values[0]));
+
+        // replaying every input from the same state predicts exactly the same, so nothing is corrected
+        let predicted = predictor.predicted.clone();
+        predictor.corrections.clear();
+        predictor.reconcile(0, &authoritative(0), None, &owned);
+        assert_eq!(predictor.predicted, predicted);
+        assert!(predictor.corrections.is_empty());
+    }
+
+    #[test]
+    fn mispredictions_are_corrected_and_fade() {
+        let owned = [TestId::Counter(0)];
+        let mut predictor : Predictor<TestApp> = Predictor::new(60);
+        predictor.reconcile(0, &authoritative(0), None, &owned);
+        predictor.input(&owned, add(5));
+        predictor.corrections.clear();
+
+        // agreeing with what was predicted needs no correction
+        let predicted = predictor.predicted.clone();
+        predictor.reconcile(1, &predicted, Some(1), &owned);
+        assert!(predictor.corrections.is_empty());
+
+        predictor.reconcile(2, &authoritative(50), Some(1), &owned);
+        let correction = predictor.corrections[&TestId::Counter(0)].clone();
+        assert_eq!(correction.from, predicted[&TestId::Counter(0)]);
+        assert_eq!(correction.weight, 1.0);
+        assert!(!predictor.corrections.contains_key(&TestId::Counter(1)));
+
+        predictor.decay(0.5);
+        assert_eq!(predictor.corrections[&TestId::Counter(0)].weight, 0.5);
+        for _ in 0..10 {
+            predictor.decay(0.5);
+        }
+        assert!(predictor.corrections.is_empty());
+    }
+}
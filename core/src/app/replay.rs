@@ -0,0 +1,230 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use event::Event;
+use network::codec::{BincodeCodec, SerializeCodec, DeserializeCodec, CodecError};
+use super::{App, Simulation};
+use super::snapshot::Snapshot;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    IO(io::Error),
+    Codec(CodecError),
+    Desync { tick: u64, expected: u64, actual: u64 }, // checksums of the entity state after the tick
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::IO(err)
+    }
+}
+
+impl From<CodecError> for ReplayError {
+    fn from(err: CodecError) -> Self {
+        ReplayError::Codec(err)
+    }
+}
+
+pub type ReplayResult<T> = Result<T, ReplayError>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayTick<Id, Entity, EntityEvent, RenderEvent> {
+    pub n: u64,
+    pub events: Vec<Event<Id, Entity, EntityEvent, RenderEvent>>, // injected from outside the simulation before this tick
+    pub checksum: u64, // of the entities after this tick
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "Id: Ord + Deserialize<'de>, Entity: Deserialize<'de>, EntityEvent: Deserialize<'de>, RenderEvent: Deserialize<'de>, IdKind: Ord + Deserialize<'de>"))]
+pub struct Replay<Id, Entity, EntityEvent, RenderEvent, IdKind> where Id : Ord, IdKind : Ord {
+    pub start: Snapshot<Id, Entity, EntityEvent, RenderEvent, IdKind>,
+    pub ticks: Vec<ReplayTick<Id, Entity, EntityEvent, RenderEvent>>,
+}
+
+pub type AppReplay<A> = Replay<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent, <A as App>::IdKind>;
+
+impl<Id, Entity, EntityEvent, RenderEvent, IdKind> Replay<Id, Entity, EntityEvent, RenderEvent, IdKind> where Id : Ord, IdKind : Ord {
+    pub fn write_to<W>(&self, writer: &mut W) -> ReplayResult<()> where W : Write, Self : ::serde::Serialize {
+        let mut bytes = Vec::new();
+        BincodeCodec::serialize(self, &mut bytes)?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn read_from<R>(reader: &mut R) -> ReplayResult<Self> where R : Read, Self : ::serde::de::DeserializeOwned {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let replay = BincodeCodec::deserialize(&bytes)?;
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &Path) -> ReplayResult<()> where Self : ::serde::Serialize {
+        let mut file = File::create(path)?;
+        self.write_to(&mut file)
+    }
+
+    pub fn load(path: &Path) -> ReplayResult<Self> where Self : ::serde::de::DeserializeOwned {
+        let mut file = File::open(path)?;
+        Self::read_from(&mut file)
+    }
+}
+
+// captures the state of a simulation, then every event injected into it
+pub struct Recorder<A> where A : App {
+    pub replay: AppReplay<A>,
+}
+
+impl<A> Recorder<A> where A : App {
+    pub fn start(simulation: &Simulation<A>) -> Recorder<A> {
+        Recorder {
+            replay: Replay {
+                start: simulation.snapshot(),
+                ticks: Vec::new(),
+            },
+        }
+    }
+
+    // use in place of simulation.step()
+    pub fn step(&mut self, simulation: &mut Simulation<A>) -> Vec<A::RenderEvent> {
+        let n = simulation.tick;
+        let events = simulation.injected().to_vec();
+        let render_events = simulation.step();
+        self.replay.ticks.push(ReplayTick {
+            n,
+            events,
+            checksum: simulation.checksum(),
+        });
+        render_events
+    }
+
+    pub fn save(&self, path: &Path) -> ReplayResult<()> {
+        self.replay.save(path)
+    }
+}
+
+// re-runs a recorded simulation, checking each tick against the recorded checksum
+pub struct ReplayPlayer<A> where A : App {
+    pub simulation: Simulation<A>,
+    pub replay: AppReplay<A>,
+    next: usize,
+}
+
+impl<A> ReplayPlayer<A> where A : App {
+    pub fn new(replay: AppReplay<A>) -> ReplayPlayer<A> {
+        let simulation = Simulation::from_snapshot(replay.start.clone());
+        ReplayPlayer {
+            simulation,
+            replay,
+            next: 0,
+        }
+    }
+
+    pub fn load(path: &Path) -> ReplayResult<ReplayPlayer<A>> {
+        let replay = Replay::load(path)?;
+        Ok(ReplayPlayer::new(replay))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.replay.ticks.len()
+    }
+
+    // steps a single recorded tick, None when the replay is exhausted
+    pub fn step(&mut self) -> Option<ReplayResult<Vec<A::RenderEvent>>> {
+        if self.finished() {
+            return None;
+        }
+
+        let (mut events, expected) = {
+            let tick = &self.replay.ticks[self.next];
+            (tick.events.clone(), tick.checksum)
+        };
+        self.next += 1;
+
+        let n = self.simulation.tick;
+        self.simulation.push_all(&mut events);
+        let render_events = self.simulation.step();
+        let actual = self.simulation.checksum();
+
+        if actual == expected {
+            Some(Ok(render_events))
+        } else {
+            Some(Err(ReplayError::Desync { tick: n, expected, actual }))
+        }
+    }
+
+    pub fn run_to_end(&mut self) -> ReplayResult<()> {
+        while let Some(result) = self.step() {
+            result?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::test_app::{TestApp, TestId, TestEvent, counters, inputs};
+
+    fn record(ticks: u64) -> AppReplay<TestApp> {
+        let mut simulation = counters(6).with_seed(7);
+        let mut recorder = Recorder::start(&simulation);
+        for n in 0..ticks {
+            simulation.push_all(&mut inputs(n));
+            recorder.step(&mut simulation);
+        }
+        recorder.replay
+    }
+
+    #[test]
+    fn replays_without_desync() {
+        let replay = record(40);
+        let expected = replay.ticks.last().map(|t| t.checksum);
+        let mut player = ReplayPlayer::<TestApp>::new(replay);
+        player.run_to_end().unwrap();
+        assert!(player.finished());
+        assert!(player.step().is_none());
+        assert_eq!(Some(player.simulation.checksum()), expected);
+    }
+
+    #[test]
+    fn survives_a_round_trip_through_bytes() {
+        let replay = record(20);
+        let mut bytes = Vec::new();
+        replay.write_to(&mut bytes).unwrap();
+        let loaded : AppReplay<TestApp> = Replay::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(loaded.ticks.len(), 20);
+        ReplayPlayer::<TestApp>::new(loaded).run_to_end().unwrap();
+    }
+
+    #[test]
+    fn reports_the_tick_a_checksum_first_differs() {
+        let mut replay = record(20);
+        replay.ticks[12].checksum ^= 1;
+        let mut player = ReplayPlayer::<TestApp>::new(replay);
+        match player.run_to_end() {
+            Err(ReplayError::Desync { tick, expected, actual }) => {
+                assert_eq!(tick, 12);
+                assert_eq!(expected ^ 1, actual);
+            },
+            other => panic!("expected a desync, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn altered_input_desyncs() {
+        let mut replay = record(20);
+        replay.ticks[5].events.push(Event::EntityEvent(TestId::Counter(2), TestEvent::Add(100)));
+        let mut player = ReplayPlayer::<TestApp>::new(replay);
+        for _ in 0..5 {
+            assert!(player.step().unwrap().is_ok());
+        }
+        match player.step() {
+            Some(Err(ReplayError::Desync { tick: 5, .. })) => (),
+            other => panic!("expected a desync on tick 5, got {:?}", other.map(|r| r.map(|_| ()))),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+
+use serde::Serialize;
+use bincode;
+use fnv::FnvHasher;
+
+use {HashMap, TreeMap};
+
+// changes between a baseline state the client acknowledged and the state after tick
+// pairs rather than maps so ids that aren't strings survive JsonCodec
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delta<Id, Entity> {
+    pub baseline: Option<u64>, // None is a full state, apply to nothing
+    pub tick: u64,
+    pub spawned: Vec<(Id, Entity)>,
+    pub updated: Vec<(Id, Entity)>,
+    pub deleted: Vec<Id>,
+}
+
+impl<Id, Entity> Delta<Id, Entity> {
+    pub fn is_full(&self) -> bool {
+        self.baseline.is_none()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.updated.is_empty() && self.deleted.is_empty()
+    }
+}
+
+pub fn entity_hash<E>(entity: &E) -> u64 where E : Serialize {
+    let bytes = bincode::serialize(entity, bincode::Infinite).expect("entities are serializable");
+    let mut hasher = FnvHasher::default();
+    hasher.write(&bytes);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientBaseline<Id> where Id : Ord {
+    pub acked: Option<u64>, // latest tick the client confirmed it holds
+    history: VecDeque<(u64, TreeMap<Id, u64>)>, // what was sent to this client, as hashes
+}
+
+// server side, remembers what each client was recently sent so it can be sent a delta against what it last acked
+// clients only ever see the entities visible to them, entering/leaving view shows up as spawns/deletes
+pub struct Replicator<Id, K> where Id : Ord, K : Hash + Eq {
+    pub max_history: usize,
+    current: Option<(u64, TreeMap<Id, u64>)>,
+    pub clients: HashMap<K, ClientBaseline<Id>>,
+}
+
+impl<Id, K> Replicator<Id, K> where Id : Ord + Clone, K : Hash + Eq {
+    pub fn new(max_history: usize) -> Replicator<Id, K> {
+        Replicator {
+            max_history,
+            current: None,
+            clients: HashMap::default(),
+        }
+    }
+
+    pub fn add_client(&mut self, client: K) {
+        self.clients.insert(client, ClientBaseline { acked: None, history: VecDeque::new() });
+    }
+
+    pub fn remove_client(&mut self, client: &K) {
+        self.clients.remove(client);
+    }
+
+    // acks for ticks we've already forgotten are ignored, the client will get a full state
+    pub fn ack(&mut self, client: &K, tick: u64) {
+        if let Some(baseline) = self.clients.get_mut(client) {
+            if baseline.acked.map(|acked| tick > acked).unwrap_or(true) {
+                baseline.acked = Some(tick);
+            }
+        }
+    }
+
+    pub fn resync(&mut self, client: &K) {
+        if let Some(baseline) = self.clients.get_mut(client) {
+            baseline.acked = None;
+        }
+    }
+
+    // hashes the state after tick once, before any delta_for
+    pub fn record<Entity>(&mut self, tick: u64, entities: &TreeMap<Id, Entity>) where Entity : Serialize {
+        let hashes = entities.iter().map(|(id, e)| (id.clone(), entity_hash(e))).collect();
+        self.current = Some((tick, hashes));
+    }
+
+    pub fn delta_for<Entity>(&mut self, client: &K, tick: u64, entities: &TreeMap<Id, Entity>) -> Delta<Id, Entity> where Entity : Serialize + Clone {
+        self.visible_delta_for(client, tick, entities, |_, _| true)
+    }
+
+    // the delta of only the entities visible is true for, record must have been called for tick
+    pub fn visible_delta_for<Entity, F>(&mut self, client: &K, tick: u64, entities: &TreeMap<Id, Entity>, visible: F) -> Delta<Id, Entity> where Entity : Serialize + Clone, F : Fn(&Id, &Entity) -> bool {
+        let current : TreeMap<Id, u64> = match self.current {
+            Some((t, ref hashes)) if t == tick => hashes.iter().filter(|&(id, _)| entities.get(id).map(|e| visible(id, e)).unwrap_or(false)).map(|(id, h)| (id.clone(), *h)).collect(),
+            _ => entities.iter().filter(|&(id, e)| visible(id, e)).map(|(id, e)| (id.clone(), entity_hash(e))).collect(),
+        };
+
+        let max_history = self.max_history;
+        match self.clients.get_mut(client) {
+            Some(client_baseline) => {
+                let delta = {
+                    let baseline = client_baseline.acked.and_then(|acked| client_baseline.history.iter().find(|&&(t, _)| t == acked));
+                    match baseline {
+                        Some(&(baseline_tick, ref baseline)) => diff(baseline_tick, baseline, tick, &current, entities),
+                        None => full_of(tick, &current, entities),
+                    }
+                };
+                client_baseline.history.push_back((tick, current));
+                while client_baseline.history.len() > max_history {
+                    client_baseline.history.pop_front();
+                }
+                delta
+            },
+            None => full_of(tick, &current, entities),
+        }
+    }
+}
+
+fn full_of<Id, Entity>(tick: u64, visible: &TreeMap<Id, u64>, entities: &TreeMap<Id, Entity>) -> Delta<Id, Entity> where Id : Ord + Clone, Entity : Clone {
+    Delta {
+        baseline: None,
+        tick,
+        spawned: visible.keys().map(|id| (id.clone(), entities[id].clone())).collect(),
+        updated: Vec::new(),
+        deleted: Vec::new(),
+    }
+}
+
+pub fn full<Id, Entity>(tick: u64, entities: &TreeMap<Id, Entity>) -> Delta<Id, Entity> where Id : Clone, Entity : Clone {
+    Delta {
+        baseline: None,
+        tick,
+        spawned: entities.iter().map(|(id, e)| (id.clone(), e.clone())).collect(),
+        updated: Vec::new(),
+        deleted: Vec::new(),
+    }
+}
+
+fn diff<Id, Entity>(baseline_tick: u64, baseline: &TreeMap<Id, u64>, tick: u64, current: &TreeMap<Id, u64>, entities: &TreeMap<Id, Entity>) -> Delta<Id, Entity> where Id : Ord + Clone, Entity : Clone {
+    let mut spawned = Vec::new();
+    let mut updated = Vec::new();
+    for (id, hash) in current {
+        match baseline.get(id) {
+            None => spawned.push((id.clone(), entities[id].clone())),
+            Some(before) if before != hash => updated.push((id.clone(), entities[id].clone())),
+            Some(_) => (),
+        }
+    }
+    let deleted = baseline.keys().filter(|id| !current.contains_key(id)).cloned().collect();
+
+    Delta {
+        baseline: Some(baseline_tick),
+        tick,
+        spawned,
+        updated,
+        deleted,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyError {
+    MissingBaseline(u64), // ask for a resync
+    Stale, // older than what we already hold, drop it
+}
+
+// client side, keeps a few recent states so deltas can be applied to whichever baseline they name
+pub struct Replica<Id, Entity> where Id : Ord {
+    pub max_history: usize,
+    states: VecDeque<(u64, TreeMap<Id, Entity>)>,
+}
+
+impl<Id, Entity> Replica<Id, Entity> where Id : Ord + Clone, Entity : Clone {
+    pub fn new(max_history: usize) -> Replica<Id, Entity> {
+        Replica {
+            max_history,
+            states: VecDeque::new(),
+        }
+    }
+
+    pub fn latest(&self) -> Option<&(u64, TreeMap<Id, Entity>)> {
+        self.states.back()
+    }
+
+    // returns the tick to ack
+    pub fn apply(&mut self, delta: Delta<Id, Entity>) -> Result<u64, ApplyError> {
+        if let Some(&(latest, _)) = self.states.back() {
+            if delta.tick <= latest {
+                return Err(ApplyError::Stale);
+            }
+        }
+
+        let mut entities = match delta.baseline {
+            None => TreeMap::new(),
+            Some(baseline) => {
+                match self.states.iter().find(|&&(t, _)| t == baseline) {
+                    Some((_, state)) => state.clone(),
+                    None => return Err(ApplyError::MissingBaseline(baseline)),
+                }
+            },
+        };
+
+        for id in delta.deleted {
+            entities.remove(&id);
+        }
+        for (id, entity) in delta.spawned.into_iter().chain(delta.updated) {
+            entities.insert(id, entity);
+        }
+
+        self.states.push_back((delta.tick, entities));
+        while self.states.len() > self.max_history {
+            self.states.pop_front();
+        }
+        Ok(delta.tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entities: &[(u64, i64)]) -> TreeMap<u64, i64> {
+        entities.iter().cloned().collect()
+    }
+
+    fn latest(replica: &Replica<u64, i64>) -> TreeMap<u64, i64> {
+        replica.latest().map(|(_, entities)| entities.clone()).unwrap_or_default()
+    }
+
+    #[test]
+    fn deltas_apply_against_the_acked_baseline() {
+        let mut replicator : Replicator<u64, u8> = Replicator::new(8);
+        let mut replica = Replica::new(8);
+        replicator.add_client(0);
+
+        let first = state(&[(1, 10), (2, 20), (3, 30)]);
+        replicator.record(1, &first);
+        let delta = replicator.delta_for(&0, 1, &first);
+        assert!(delta.is_full());
+        assert_eq!(replica.apply(delta), Ok(1));
+        replicator.ack(&0, 1);
+
+        let second = state(&[(1, 10), (2, 21), (4, 40)]);
+        replicator.record(2, &second);
+        let delta = replicator.delta_for(&0, 2, &second);
+        assert_eq!(delta.baseline, Some(1));
+        assert_eq!(delta.spawned, vec![(4, 40)]);
+        assert_eq!(delta.updated, vec![(2, 21)]);
+        assert_eq!(delta.deleted, vec![3]);
+        assert_eq!(replica.apply(delta), Ok(2));
+        assert_eq!(latest(&replica), second);
+    }
+
+    #[test]
+    fn lost_deltas_are_covered_by_the_next_one_against_the_same_ack() {
+        let mut replicator : Replicator<u64, u8> = Replicator::new(8);
+        let mut replica = Replica::new(8);
+        replicator.add_client(0);
+
+        let first = state(&[(1, 10), (2, 20)]);
+        replicator.record(1, &first);
+        replica.apply(replicator.delta_for(&0, 1, &first)).unwrap();
+        replicator.ack(&0, 1);
+
+        // never reaches the client
+        let second = state(&[(1, 11), (2, 20)]);
+        replicator.record(2, &second);
+        replicator.delta_for(&0, 2, &second);
+
+        let third = state(&[(1, 11), (2, 22)]);
+        replicator.record(3, &third);
+        let delta = replicator.delta_for(&0, 3, &third);
+        assert_eq!(delta.baseline, Some(1));
+        assert_eq!(delta.updated, vec![(1, 11), (2, 22)]);
+        assert_eq!(replica.apply(delta), Ok(3));
+        assert_eq!(latest(&replica), third);
+    }
+
+    #[test]
+    fn missing_baselines_and_stale_deltas_are_refused() {
+        let mut replicator : Replicator<u64, u8> = Replicator::new(2);
+        let mut replica = Replica::new(8);
+        replicator.add_client(0);
+
+        let entities = state(&[(1, 10)]);
+        replicator.record(1, &entities);
+        replicator.delta_for(&0, 1, &entities);
+        replicator.ack(&0, 1);
+        replicator.record(2, &entities);
+        let delta = replicator.delta_for(&0, 2, &entities);
+        assert_eq!(replica.apply(delta), Err(ApplyError::MissingBaseline(1)));
+
+        replicator.resync(&0);
+        replicator.record(3, &entities);
+        let full = replicator.delta_for(&0, 3, &entities);
+        assert!(full.is_full());
+        assert_eq!(replica.apply(full.clone()), Ok(3));
+        assert_eq!(replica.apply(full), Err(ApplyError::Stale));
+        assert_eq!(latest(&replica), entities);
+
+        // acks the server has already forgotten fall back to full states
+        replicator.ack(&0, 3);
+        for tick in 4..7 {
+            replicator.record(tick, &entities);
+            replicator.delta_for(&0, tick, &entities);
+        }
+        replicator.record(7, &entities);
+        assert!(replicator.delta_for(&0, 7, &entities).is_full());
+    }
+
+    #[test]
+    fn entities_leaving_view_arrive_as_deletes() {
+        let mut replicator : Replicator<u64, u8> = Replicator::new(8);
+        let mut replica = Replica::new(8);
+        replicator.add_client(0);
+
+        let first = state(&[(1, 10), (2, 20)]);
+        replicator.record(1, &first);
+        replica.apply(replicator.visible_delta_for(&0, 1, &first, |_, e| *e < 25)).unwrap();
+        replicator.ack(&0, 1);
+
+        let second = state(&[(1, 30), (2, 20)]);
+        replicator.record(2, &second);
+        let delta = replicator.visible_delta_for(&0, 2, &second, |_, e| *e < 25);
+        assert_eq!(delta.deleted, vec![1]);
+        assert!(delta.spawned.is_empty() && delta.updated.is_empty());
+        replica.apply(delta).unwrap();
+        assert_eq!(latest(&replica), state(&[(2, 20)]));
+    }
+}
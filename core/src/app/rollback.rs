@@ -0,0 +1,332 @@
+use TreeMap;
+use super::{App, AppEvent, Simulation, Diagnostics, DiagnosticsMode};
+use super::snapshot::AppSnapshot;
+use super::messages::{PeerId, PeerMessage, AppPeerMessage};
+use super::replication::entity_hash;
+
+pub const CHECKSUM_HISTORY : u64 = 600; // ticks of local checksums kept for late remote checksums
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InputPrediction {
+    Repeat, // assume the peer is still doing what it last did, suits held input
+    Empty, // assume the peer did nothing
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Desync {
+    pub tick: u64,
+    pub peer: PeerId,
+    pub local: u64,
+    pub remote: u64,
+}
+
+// every peer runs the whole simulation, only input events are exchanged
+// missing remote input is predicted so we never wait on the network, when it arrives and differs
+// from the prediction we restore the snapshot from before that tick and resimulate up to the present
+// the transport is up to the caller, drain outbound to every peer and hand everything received to receive
+pub struct RollbackSession<A> where A : App {
+    pub simulation: Simulation<A>,
+    pub local: PeerId,
+    pub peers: Vec<PeerId>, // sorted, including local, each tick's inputs are pushed in this order
+    pub max_rollback: u64, // we stop advancing rather than get further than this ahead of the last confirmed tick
+    pub prediction: InputPrediction,
+    pub outbound: Vec<AppPeerMessage<A>>,
+    pub desyncs: Vec<Desync>,
+    pub resimulated: u64, // total ticks simulated again due to mispredictions
+    start: u64,
+    confirmed: Option<u64>, // every tick up to and including this has input from every peer
+    rollback_to: Option<u64>,
+    inputs: TreeMap<(u64, PeerId), Vec<AppEvent<A>>>, // as sent by each peer
+    predicted: TreeMap<(u64, PeerId), Vec<AppEvent<A>>>, // used in place of input we didn't have yet
+    snapshots: TreeMap<u64, AppSnapshot<A>>, // the state before tick was simulated
+    checksums: TreeMap<u64, u64>, // ours, after tick
+    remote_checksums: TreeMap<(u64, PeerId), u64>,
+}
+
+impl<A> RollbackSession<A> where A : App {
+    pub fn new(simulation: Simulation<A>, local: PeerId, peers: Vec<PeerId>, max_rollback: u64) -> RollbackSession<A> {
+        let mut peers = peers;
+        if !peers.contains(&local) {
+            peers.push(local);
+        }
+        peers.sort();
+        peers.dedup();
+
+        let start = simulation.tick;
+        RollbackSession {
+            simulation,
+            local,
+            peers,
+            max_rollback,
+            prediction: InputPrediction::Repeat,
+            outbound: Vec::new(),
+            desyncs: Vec::new(),
+            resimulated: 0,
+            start,
+            confirmed: None,
+            rollback_to: None,
+            inputs: TreeMap::new(),
+            predicted: TreeMap::new(),
+            snapshots: TreeMap::new(),
+            checksums: TreeMap::new(),
+            remote_checksums: TreeMap::new(),
+        }
+    }
+
+    pub fn confirmed_tick(&self) -> Option<u64> {
+        self.confirmed
+    }
+
+    // simulates one tick with our input, None (leaving local_events alone) when we're max_rollback ahead of a peer
+    // only this tick's render events are returned, those from resimulated ticks are dropped
+    pub fn advance(&mut self, local_events: &mut Vec<AppEvent<A>>) -> Option<Vec<A::RenderEvent>> {
+        self.rollback();
+        self.confirm(); // input that arrived while we were stalled may settle enough ticks to go on
+
+        let tick = self.simulation.tick;
+        let first_unconfirmed = self.confirmed.map(|c| c + 1).unwrap_or(self.start);
+        if tick - first_unconfirmed >= self.max_rollback {
+            return None;
+        }
+
+        let events = ::std::mem::take(local_events);
+        self.outbound.push(PeerMessage::Input { peer: self.local, tick, events: events.clone() });
+        self.inputs.insert((tick, self.local), events);
+
+        let render_events = self.simulate_tick();
+        self.confirm();
+        Some(render_events)
+    }
+
+    // messages for ticks before the session started or further ahead than we'd ever let ourselves get are dropped
+    pub fn receive(&mut self, message: AppPeerMessage<A>) {
+        let tick = match message {
+            PeerMessage::Input { tick, .. } | PeerMessage::Checksum { tick, .. } => tick,
+        };
+        if tick < self.start || tick > self.simulation.tick.saturating_add(self.max_rollback) {
+            return;
+        }
+        match message {
+            PeerMessage::Input { peer, tick, events } => {
+                if !self.peers.contains(&peer) || self.inputs.contains_key(&(tick, peer)) {
+                    return;
+                }
+                if let Some(predicted) = self.predicted.remove(&(tick, peer)) {
+                    if entity_hash(&predicted) != entity_hash(&events) {
+                        self.rollback_to = Some(self.rollback_to.map(|r| r.min(tick)).unwrap_or(tick));
+                    }
+                }
+                self.inputs.insert((tick, peer), events);
+            },
+            PeerMessage::Checksum { peer, tick, checksum } => {
+                self.remote_checksums.insert((tick, peer), checksum);
+                self.check_desyncs();
+            },
+        }
+    }
+
+    pub fn take_outbound(&mut self) -> Vec<AppPeerMessage<A>> {
+        self.outbound.split_off(0)
+    }
+
+    fn simulate_tick(&mut self) -> Vec<A::RenderEvent> {
+        let tick = self.simulation.tick;
+        self.snapshots.insert(tick, self.simulation.snapshot());
+
+        for peer in self.peers.clone() {
+            let mut events = match self.inputs.get(&(tick, peer)) {
+                Some(events) => events.clone(),
+                None => {
+                    let events = self.predict(tick, peer);
+                    self.predicted.insert((tick, peer), events.clone());
+                    events
+                },
+            };
+            self.simulation.push_all(&mut events);
+        }
+
+        let render_events = self.simulation.step();
+        self.checksums.insert(tick, self.simulation.checksum());
+        render_events
+    }
+
+    fn predict(&self, tick: u64, peer: PeerId) -> Vec<AppEvent<A>> {
+        match self.prediction {
+            InputPrediction::Repeat => self.inputs.range(..(tick, 0)).rev().find(|&(&(_, p), _)| p == peer).map(|(_, events)| events.clone()).unwrap_or_else(Vec::new),
+            InputPrediction::Empty => Vec::new(),
+        }
+    }
+
+    fn rollback(&mut self) {
+        let from = match self.rollback_to.take() {
+            Some(from) => from,
+            None => return,
+        };
+        let snapshot = match self.snapshots.get(&from) {
+            Some(snapshot) => snapshot.clone(),
+            None => return,
+        };
+        let to = self.simulation.tick;
+
+        let step_mode = self.simulation.step_mode;
+        let diagnostics = ::std::mem::replace(&mut self.simulation.diagnostics, Diagnostics::new(DiagnosticsMode::Ignore));
+        self.simulation = Simulation::from_snapshot(snapshot).with_step_mode(step_mode);
+        self.simulation.diagnostics = diagnostics;
+
+        for _ in from..to {
+            self.simulate_tick();
+        }
+        self.resimulated += to - from;
+    }
+
+    // ticks whose input is complete (and which won't be rolled back) are settled, their checksums go out
+    fn confirm(&mut self) {
+        let mut tick = self.confirmed.map(|c| c + 1).unwrap_or(self.start);
+        while tick < self.simulation.tick && self.rollback_to.map(|r| tick < r).unwrap_or(true) && self.peers.iter().all(|p| self.inputs.contains_key(&(tick, *p))) {
+            if let Some(&checksum) = self.checksums.get(&tick) {
+                self.outbound.push(PeerMessage::Checksum { peer: self.local, tick, checksum });
+            }
+            self.confirmed = Some(tick);
+            tick += 1;
+        }
+
+        if let Some(confirmed) = self.confirmed {
+            // the last confirmed input of every peer is kept for prediction, the snapshot after it for rollback
+            self.inputs = self.inputs.split_off(&(confirmed, 0));
+            self.predicted = self.predicted.split_off(&(confirmed + 1, 0));
+            self.snapshots = self.snapshots.split_off(&(confirmed + 1));
+            self.checksums = self.checksums.split_off(&confirmed.saturating_sub(CHECKSUM_HISTORY));
+        }
+        self.check_desyncs();
+    }
+
+    fn check_desyncs(&mut self) {
+        let confirmed = match self.confirmed {
+            Some(confirmed) => confirmed,
+            None => return,
+        };
+        let oldest = confirmed.saturating_sub(CHECKSUM_HISTORY);
+        let settled : Vec<(u64, PeerId)> = self.remote_checksums.keys().filter(|&&(tick, _)| tick <= confirmed).cloned().collect();
+        for (tick, peer) in settled {
+            let remote = self.remote_checksums.remove(&(tick, peer)).expect("key was just listed");
+            if tick < oldest {
+                continue;
+            }
+            if let Some(&local) = self.checksums.get(&tick) {
+                if local != remote {
+                    self.desyncs.push(Desync { tick, peer, local, remote });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::Event;
+    use app::test_app::{TestApp, TestId, TestEvent, counters};
+
+    // changes every tick so repeating the last input always mispredicts
+    fn input(peer: PeerId, tick: u64) -> Vec<AppEvent<TestApp>> {
+        vec![Event::EntityEvent(TestId::Counter(peer as u64), TestEvent::Add((tick * (peer as u64 + 1)) as i64))]
+    }
+
+    fn deliver(messages: Vec<AppPeerMessage<TestApp>>, to: &mut RollbackSession<TestApp>) {
+        for message in messages {
+            to.receive(message);
+        }
+    }
+
+    #[test]
+    fn late_input_is_resimulated_to_the_serial_result() {
+        let mut a = RollbackSession::new(counters(4), 0, vec![0, 1], 8);
+        let mut b = RollbackSession::new(counters(4), 1, vec![0, 1], 8);
+        let mut reference = counters(4);
+        let mut delayed = Vec::new();
+
+        for tick in 0..30 {
+            let (mut a_input, mut b_input) = if tick < 20 { (input(0, tick), input(1, tick)) } else { (Vec::new(), Vec::new()) };
+            reference.push_all(&mut a_input.clone());
+            reference.push_all(&mut b_input.clone());
+            reference.step();
+
+            assert!(a.advance(&mut a_input).is_some());
+            deliver(a.take_outbound(), &mut b);
+            assert!(b.advance(&mut b_input).is_some());
+
+            // b's messages reach a three ticks late
+            delayed.push(b.take_outbound());
+            if delayed.len() > 3 {
+                deliver(delayed.remove(0), &mut a);
+            }
+        }
+        for messages in delayed {
+            deliver(messages, &mut a);
+        }
+        assert!(a.advance(&mut Vec::new()).is_some());
+        deliver(a.take_outbound(), &mut b);
+        assert!(b.advance(&mut Vec::new()).is_some());
+        reference.step();
+
+        assert!(a.resimulated > 0);
+        assert_eq!(b.resimulated, 0);
+        assert_eq!(a.simulation.entities, reference.entities);
+        assert_eq!(a.simulation.checksum(), reference.checksum());
+        assert_eq!(b.simulation.checksum(), reference.checksum());
+        assert!(a.desyncs.is_empty() && b.desyncs.is_empty());
+    }
+
+    #[test]
+    fn stalls_when_too_far_ahead_of_a_peer() {
+        let mut a = RollbackSession::new(counters(2), 0, vec![0, 1], 4);
+        for tick in 0..4 {
+            assert!(a.advance(&mut input(0, tick)).is_some());
+        }
+        let mut held = input(0, 4);
+        assert!(a.advance(&mut held).is_none());
+        assert_eq!(held.len(), 1);
+        assert_eq!(a.confirmed_tick(), None);
+
+        for tick in 0..4 {
+            a.receive(PeerMessage::Input { peer: 1, tick, events: Vec::new() });
+        }
+        assert!(a.advance(&mut held).is_some());
+        assert_eq!(a.confirmed_tick(), Some(3));
+    }
+
+    #[test]
+    fn messages_outside_the_rollback_window_are_dropped() {
+        let mut a = RollbackSession::new(counters(2), 0, vec![0, 1], 8);
+        a.receive(PeerMessage::Input { peer: 1, tick: u64::MAX - 3, events: Vec::new() });
+        a.receive(PeerMessage::Input { peer: 1, tick: 9, events: Vec::new() });
+        a.receive(PeerMessage::Checksum { peer: 1, tick: u64::MAX, checksum: 1 });
+        assert!(a.inputs.is_empty());
+        assert!(a.remote_checksums.is_empty());
+
+        a.receive(PeerMessage::Input { peer: 1, tick: 8, events: Vec::new() });
+        assert_eq!(a.inputs.len(), 1);
+
+        // a session joined at tick 5 has no use for anything before it
+        let mut simulation = counters(2);
+        for _ in 0..5 {
+            simulation.step();
+        }
+        let mut late = RollbackSession::new(simulation, 0, vec![0, 1], 8);
+        late.receive(PeerMessage::Checksum { peer: 1, tick: 4, checksum: 1 });
+        assert!(late.remote_checksums.is_empty());
+    }
+
+    #[test]
+    fn differing_checksums_are_reported() {
+        let mut a = RollbackSession::new(counters(2), 0, vec![0, 1], 8);
+        a.receive(PeerMessage::Input { peer: 1, tick: 0, events: Vec::new() });
+        a.advance(&mut Vec::new());
+        let local = a.simulation.checksum();
+
+        a.receive(PeerMessage::Checksum { peer: 1, tick: 0, checksum: local });
+        assert!(a.desyncs.is_empty());
+        a.receive(PeerMessage::Checksum { peer: 1, tick: 0, checksum: local + 1 });
+        assert_eq!(a.desyncs, vec![Desync { tick: 0, peer: 1, local, remote: local + 1 }]);
+    }
+}
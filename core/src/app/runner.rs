@@ -0,0 +1,342 @@
+use std::mem;
+use std::hash::Hasher;
+use std::collections::Bound::Included;
+
+use multimap::MultiMap;
+use bincode;
+use fnv::FnvHasher;
+use rayon::prelude::*;
+
+use {Tick, TreeMap, SimRng};
+use event::*;
+use super::{App, AppEvent, SimSettings};
+use super::ids::{IdAllocator, IdLease, max_taken};
+use super::timers::TimerQueue;
+use super::diagnostics::{Diagnostic, Diagnostics, DiagnosticsMode};
+use super::spatial::{SpatialGrid, World};
+use super::snapshot::{AppSnapshot, Snapshot, SnapshotHeader, SnapshotBody};
+
+pub const NANOSECONDS_IN_A_SECOND : u64 = 1_000_000_000u64;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StepMode {
+    Serial,
+    Parallel, // entities are simulated across the rayon pool, results are identical to Serial
+}
+
+// the fixed timestep simulation without any notion of a window, renderer or wall clock
+// the caller decides when to step (a render loop, a dedicated server, a test)
+pub struct Simulation<A> where A : App {
+    pub settings: SimSettings,
+    pub tick: u64,
+    pub ids: IdAllocator<A::IdKind>,
+    pub rng: SimRng, // advanced once per step, each entity gets a fork of it
+    pub timers: TimerQueue<A::Id, A::EntityEvent>,
+    pub running: bool, // false once a Shutdown event has been routed
+    pub last_entities: TreeMap<A::Id, A::Entity>, // state at the start of the last step, after routing
+    pub entities: TreeMap<A::Id, A::Entity>,
+    to_route: Vec<(Option<A::Id>, AppEvent<A>)>, // produced by the simulation itself (and by whom), routed on the next step
+    injected: Vec<AppEvent<A>>, // pushed from the outside (input, network), routed on the next step
+    pub step_mode: StepMode,
+    pub diagnostics: Diagnostics<A::Id>,
+}
+
+impl<A> Simulation<A> where A : App {
+    pub fn new(settings: SimSettings, initial_entities: TreeMap<A::Id, A::Entity>) -> Simulation<A> {
+        let mut ids = IdAllocator::new();
+        for id in initial_entities.keys() {
+            ids.observe_id::<A>(id);
+        }
+        Simulation {
+            settings,
+            tick: 0,
+            ids,
+            rng: SimRng::seeded(0),
+            timers: TimerQueue::new(),
+            running: true,
+            last_entities: initial_entities.clone(),
+            entities: initial_entities,
+            to_route: Vec::new(),
+            injected: Vec::new(),
+            step_mode: StepMode::Serial,
+            diagnostics: Diagnostics::new(DiagnosticsMode::Warn),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: AppSnapshot<A>) -> Simulation<A> {
+        let header = snapshot.header;
+        let body = snapshot.body;
+        Simulation {
+            settings: SimSettings { tick_rate: header.tick_rate },
+            tick: header.tick,
+            ids: body.ids,
+            rng: header.rng,
+            timers: body.timers,
+            running: true,
+            last_entities: body.entities.clone(),
+            entities: body.entities,
+            to_route: body.pending,
+            injected: Vec::new(),
+            step_mode: StepMode::Serial,
+            diagnostics: Diagnostics::new(DiagnosticsMode::Warn),
+        }
+    }
+
+    pub fn snapshot(&self) -> AppSnapshot<A> {
+        Snapshot {
+            header: SnapshotHeader {
+                schema_version: A::schema_version(),
+                tick: self.tick,
+                rng: self.rng,
+                tick_rate: self.settings.tick_rate,
+            },
+            body: SnapshotBody {
+                entities: self.entities.clone(),
+                pending: self.to_route.clone(),
+                ids: self.ids.clone(),
+                timers: self.timers.clone(),
+            },
+        }
+    }
+
+    pub fn pending(&self) -> &[(Option<A::Id>, AppEvent<A>)] {
+        &self.to_route
+    }
+
+    pub fn injected(&self) -> &[AppEvent<A>] {
+        &self.injected
+    }
+
+    // fnv of the bincode encoded entities, stable across runs and machines
+    pub fn checksum(&self) -> u64 {
+        let bytes = bincode::serialize(&self.entities, bincode::Infinite).expect("entities are serializable");
+        let mut hasher = FnvHasher::default();
+        hasher.write(&bytes);
+        hasher.finish()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Simulation<A> {
+        self.rng = SimRng::seeded(seed);
+        self
+    }
+
+    pub fn with_diagnostics(mut self, mode: DiagnosticsMode) -> Simulation<A> {
+        self.diagnostics = Diagnostics::new(mode);
+        self
+    }
+
+    pub fn with_step_mode(mut self, step_mode: StepMode) -> Simulation<A> {
+        self.step_mode = step_mode;
+        self
+    }
+
+    pub fn push(&mut self, event: AppEvent<A>) {
+        self.injected.push(event);
+    }
+
+    pub fn push_all(&mut self, events: &mut Vec<AppEvent<A>>) {
+        self.injected.append(events);
+    }
+
+    pub fn per_tick_ns(&self) -> u64 {
+        NANOSECONDS_IN_A_SECOND / self.settings.tick_rate
+    }
+
+    pub fn current_tick(&self) -> Tick {
+        let per_tick_ns = self.per_tick_ns();
+        Tick {
+            n: self.tick,
+            tick_duration: (per_tick_ns as f64) / (NANOSECONDS_IN_A_SECOND as f64),
+            tick_rate: self.settings.tick_rate, // per second
+        }
+    }
+
+    // routes everything pending, then handles entity events and simulates every entity once
+    // returns the render events routed this step
+    pub fn step(&mut self) -> Vec<A::RenderEvent> {
+        let simulate_tick = self.current_tick();
+        let tick_rng = SimRng::seeded(self.rng.next_u64());
+
+        let mut last_entities = mem::take(&mut self.entities);
+
+        let mut entity_events = MultiMap::new();
+        let mut render_events = Vec::new();
+
+        let mut routing = mem::take(&mut self.to_route);
+        routing.extend(self.injected.drain(..).map(|ev| (None, ev)));
+
+        let n = self.tick;
+
+        for (origin, ev) in routing {
+            match ev {
+                Event::Shutdown => self.running = false,
+                Event::SpawnEvent(id, entity) => {
+                    self.ids.observe_id::<A>(&id);
+                    if last_entities.contains_key(&id) {
+                        self.diagnostics.report(Diagnostic::OverwrittenSpawn { tick: n, id: id.clone(), origin });
+                    }
+                    last_entities.insert(id, entity);
+                },
+                Event::Delete(id) => {
+                    if last_entities.remove(&id).is_none() {
+                        self.diagnostics.report(Diagnostic::UnknownDelete { tick: n, id, origin });
+                    }
+                },
+                Event::DeleteRange(from, to) => {
+                    let to_delete : Vec<A::Id> = last_entities.range((Included(&from), Included(&to))).map(|(k, _)| k.clone()).collect();
+                    for k in to_delete {
+                        last_entities.remove(&k);
+                    }
+                },
+                Event::EntityEvent(id, entity_event) => entity_events.insert(id, (origin, entity_event)),
+                Event::RenderEvent(render_event) => render_events.push(render_event),
+                Event::ScheduleEvent(timer_id, delivery, id, entity_event) => {
+                    let due = TimerQueue::<A::Id, A::EntityEvent>::due_tick(delivery, n);
+                    if !self.timers.schedule(timer_id, due, id, entity_event, origin.clone()) {
+                        self.diagnostics.report(Diagnostic::DuplicateTimer { tick: n, timer_id, origin });
+                    }
+                },
+                Event::CancelEvent(timer_id) => {
+                    self.timers.cancel(timer_id);
+                },
+            }
+        }
+
+        // timers land after everything routed this tick
+        for (_, timer) in self.timers.pop_due(n) {
+            entity_events.insert(timer.target, (timer.origin, timer.event));
+        }
+
+        for (target, events) in entity_events.iter_all() {
+            if !last_entities.contains_key(target) {
+                for (origin, _) in events {
+                    self.diagnostics.report(Diagnostic::UndeliveredEvent { tick: n, target: target.clone(), origin: origin.clone() });
+                }
+            }
+        }
+
+        let index = SpatialGrid::build(A::spatial_cell_size(), &last_entities, A::locate);
+        let world = World { entities: &last_entities, index: &index };
+
+        let stride = last_entities.len();
+        let leases = self.ids.leases(stride);
+
+        let stepped : Vec<Stepped<A>> = match self.step_mode {
+            StepMode::Serial => {
+                last_entities.iter().zip(leases).enumerate().map(|(i, ((id, e), lease))| step_entity::<A>(simulate_tick, tick_rng.fork(i as u64), lease, &world, &entity_events, id, e)).collect()
+            },
+            StepMode::Parallel => {
+                let work : Vec<_> = last_entities.iter().zip(leases).collect();
+                work.into_par_iter().enumerate().map(|(i, ((id, e), lease))| step_entity::<A>(simulate_tick, tick_rng.fork(i as u64), lease, &world, &entity_events, id, e)).collect()
+            },
+        };
+
+        // merged in id order, so both modes route identically
+        let mut to_route = Vec::new();
+        let mut entities = TreeMap::new();
+        let mut taken = TreeMap::new();
+        let mut timers_taken = 0;
+        for (id, entity, events, lease) in stepped {
+            to_route.extend(events.into_iter().map(|ev| (Some(id.clone()), ev)));
+            entities.insert(id, entity);
+            max_taken(&mut taken, &lease.taken);
+            timers_taken = timers_taken.max(lease.timers_taken);
+        }
+        self.ids.commit(stride, &taken, timers_taken);
+
+        self.to_route = to_route;
+        self.last_entities = last_entities;
+        self.entities = entities;
+        self.tick += 1;
+
+        render_events
+    }
+}
+
+type EntityEvents<A> = MultiMap<<A as App>::Id, (Option<<A as App>::Id>, <A as App>::EntityEvent)>;
+
+type Stepped<A> = (<A as App>::Id, <A as App>::Entity, Vec<AppEvent<A>>, IdLease<<A as App>::IdKind>);
+
+// handles last tick's events for a single entity then simulates it, returning the new entity and everything it routed
+// the rng and the id lease are threaded through every sink in turn so the entity sees a single deterministic stream of each
+fn step_entity<A>(tick: Tick, rng: SimRng, lease: IdLease<A::IdKind>, world: &World<A::Id, A::Entity>, entity_events: &EntityEvents<A>, id: &A::Id, e: &A::Entity) -> Stepped<A> where A : App {
+    let mut entity = e.clone();
+    let mut to_route = Vec::new();
+    let mut rng = rng;
+    let mut lease = lease;
+
+    // handle events from last frame
+    if let Some(evs) = entity_events.get_vec(id) {
+        for (_, event) in evs {
+            let mut sink = Sink::with(rng, lease);
+            A::handle_entity_event(event, id, &mut entity, &mut sink);
+            to_route.append(&mut sink.events);
+            rng = sink.rng;
+            lease = sink.ids;
+        }
+    }
+
+    // simulate entity
+    let mut combined_sink = CombinedSink::with(rng, lease);
+    A::simulate(tick, world, id, &entity, &mut combined_sink);
+    rng = combined_sink.rng;
+    lease = combined_sink.ids;
+
+    to_route.append(&mut combined_sink.routed.events);
+
+    // handle self effects immediately
+    for event in &combined_sink.mine.events {
+        let mut sink = Sink::with(rng, lease);
+        A::handle_entity_event(event, id, &mut entity, &mut sink);
+        to_route.append(&mut sink.events);
+        rng = sink.rng;
+        lease = sink.ids;
+    }
+
+    (id.clone(), entity, to_route, lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::test_app::{TestApp, TestId, counters, inputs};
+
+    fn run(step_mode: StepMode, ticks: u64) -> (Simulation<TestApp>, Vec<Vec<i64>>, Vec<u64>) {
+        let mut simulation = counters(40).with_seed(11).with_step_mode(step_mode).with_diagnostics(DiagnosticsMode::Ignore);
+        let mut rendered = Vec::new();
+        let mut checksums = Vec::new();
+        for n in 0..ticks {
+            simulation.push_all(&mut inputs(n));
+            rendered.push(simulation.step());
+            checksums.push(simulation.checksum());
+        }
+        (simulation, rendered, checksums)
+    }
+
+    #[test]
+    fn parallel_steps_match_serial() {
+        let (serial, serial_rendered, serial_checksums) = run(StepMode::Serial, 60);
+        let (parallel, parallel_rendered, parallel_checksums) = run(StepMode::Parallel, 60);
+
+        assert!(serial.entities.len() > 40, "sparks should have been spawned");
+        assert_eq!(serial.entities, parallel.entities);
+        assert_eq!(serial_checksums, parallel_checksums);
+        assert_eq!(serial_rendered, parallel_rendered);
+        assert_eq!(serial.ids, parallel.ids);
+        assert_eq!(serial.rng, parallel.rng);
+        assert_eq!(serial.pending(), parallel.pending());
+    }
+
+    #[test]
+    fn ids_reserved_in_handlers_and_simulate_never_collide() {
+        let mut simulation = counters(8).with_seed(2).with_diagnostics(DiagnosticsMode::Collect);
+        let mut sparks = 0;
+        for n in 0..60 {
+            simulation.push_all(&mut inputs(n));
+            simulation.step();
+            sparks += simulation.pending().iter().filter(|(_, ev)| matches!(ev, Event::SpawnEvent(TestId::Spark(_), _))).count();
+        }
+        assert!(sparks > 10);
+        assert_eq!(simulation.diagnostics.drain(), vec![]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::UnboundedSender;
+
+use HashMap;
+use network::{PuckNetworkResult, TcpConfig};
+use network::heartbeat::Latency;
+use network::queue::QueueMetrics;
+use network::codec::AsymmetricCodec;
+use network::server::{ServerEventHandler, ServerInboundEvent, run_server};
+use network::PoisonPill;
+use super::{App, AppEvent, Simulation};
+use super::messages::{ClientMessage, ServerMessage, AppClientMessage, AppServerMessage};
+use super::replication::Replicator;
+use super::ownership::{Owner, Ownership};
+
+pub const REPLICATION_HISTORY : usize = 64; // ticks, a client acking slower than this gets full states
+
+pub type AppServerInboundEvent<A> = ServerInboundEvent<AppClientMessage<A>, AppServerMessage<A>>;
+
+// hosts the authoritative simulation, clients send events in and receive the resulting state
+pub struct AppServer<A> where A : App {
+    pub simulation: Simulation<A>,
+    pub bind_address: SocketAddr,
+    pub clients: HashMap<SocketAddr, UnboundedSender<AppServerMessage<A>>>,
+    pub replicator: Replicator<A::Id, SocketAddr>,
+    pub viewers: HashMap<SocketAddr, A::Id>, // clients without a viewer see everything
+    pub ownership: Ownership<A::Id, SocketAddr>,
+    pub inputs_applied: HashMap<SocketAddr, u64>, // latest Input seq pushed into the simulation per client
+    pub latencies: HashMap<SocketAddr, Latency>, // smoothed round trip per client, for lag compensation
+    pub queues: HashMap<SocketAddr, QueueMetrics>, // how far behind the network is in writing to each client
+    overrides: Vec<AppEvent<A>>, // pushed after every client event of the same step, so the server has the last word
+    pub finished: bool, // the network side has stopped (failed to bind or shut down)
+    inbound: Receiver<AppServerInboundEvent<A>>,
+    poison_pill: Option<PoisonPill>, // None when something else drives the events
+}
+
+pub fn run_app_server<A, C>(simulation: Simulation<A>, bind_address: SocketAddr, config: TcpConfig) -> PuckNetworkResult<AppServer<A>> where A : App, C : AsymmetricCodec<AppClientMessage<A>, AppServerMessage<A>> {
+    let (sender, receiver) = channel();
+    let poison_pill = run_server::<AppClientMessage<A>, AppServerMessage<A>, C>(ServerEventHandler { sender }, bind_address, A::schema_hash(), config)?;
+
+    Ok(AppServer::from_events(simulation, bind_address, receiver, Some(poison_pill)))
+}
+
+impl<A> AppServer<A> where A : App {
+    // for transports other than run_server, e.g. a Loopback fed by the receiver's sender
+    pub fn from_events(simulation: Simulation<A>, bind_address: SocketAddr, inbound: Receiver<AppServerInboundEvent<A>>, poison_pill: Option<PoisonPill>) -> AppServer<A> {
+        AppServer {
+            simulation,
+            bind_address,
+            clients: HashMap::default(),
+            replicator: Replicator::new(REPLICATION_HISTORY),
+            viewers: HashMap::default(),
+            ownership: Ownership::new(),
+            inputs_applied: HashMap::default(),
+            latencies: HashMap::default(),
+            queues: HashMap::default(),
+            overrides: Vec::new(),
+            finished: false,
+            inbound,
+            poison_pill,
+        }
+    }
+
+    // handles everything the network layer has delivered so far
+    pub fn poll(&mut self) {
+        loop {
+            match self.inbound.try_recv() {
+                Ok(event) => self.handle(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.finished = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    fn handle(&mut self, event: AppServerInboundEvent<A>) {
+        match event {
+            ServerInboundEvent::ClientConnected { address, client_sender, queue } => {
+                // the first state goes out with the next step, against no baseline
+                let welcome = ServerMessage::Welcome { tick_rate: self.simulation.settings.tick_rate, tick: self.simulation.tick };
+                if client_sender.unbounded_send(welcome).is_ok() {
+                    self.clients.insert(address, client_sender);
+                    self.queues.insert(address, queue);
+                    self.replicator.add_client(address);
+                }
+            },
+            ServerInboundEvent::ClientMessage { address, event: ClientMessage::Events(events) } => {
+                self.push_client_events(address, events);
+            },
+            ServerInboundEvent::ClientMessage { address, event: ClientMessage::Input { seq, events } } => {
+                self.push_client_events(address, events);
+                let applied = self.inputs_applied.entry(address).or_insert(0);
+                *applied = (*applied).max(seq);
+            },
+            ServerInboundEvent::ClientMessage { address, event: ClientMessage::Ack { tick } } => {
+                self.replicator.ack(&address, tick);
+            },
+            ServerInboundEvent::ClientMessage { address, event: ClientMessage::Resync } => {
+                self.replicator.resync(&address);
+            },
+            ServerInboundEvent::ClientLatency { address, latency } => {
+                if self.clients.contains_key(&address) {
+                    self.latencies.insert(address, latency);
+                }
+            },
+            ServerInboundEvent::ClientDisconnected { address, .. } => {
+                self.remove_client(&address);
+            },
+            ServerInboundEvent::ClientRejected { .. } => (), // never got as far as being a client
+            ServerInboundEvent::FailureToBind { .. } | ServerInboundEvent::ServerFinished { .. } => {
+                self.finished = true;
+            },
+        }
+    }
+
+    // clients may only drive the entities they own
+    fn push_client_events(&mut self, address: SocketAddr, events: Vec<AppEvent<A>>) {
+        for ev in events {
+            match self.ownership.validate(&address, &ev) {
+                Ok(()) => self.simulation.push(ev),
+                Err(err) => println!("AppServer :: rejecting event from client {:?} -> {:?}", address, err),
+            }
+        }
+    }
+
+    pub fn broadcast(&mut self, message: AppServerMessage<A>) {
+        let mut gone = Vec::new();
+        for (address, sender) in &self.clients {
+            if sender.unbounded_send(message.clone()).is_err() {
+                gone.push(*address);
+            }
+        }
+        for address in gone {
+            self.remove_client(&address);
+        }
+    }
+
+    fn remove_client(&mut self, address: &SocketAddr) {
+        self.clients.remove(address);
+        self.replicator.remove_client(address);
+        self.viewers.remove(address);
+        self.ownership.remove_client(address);
+        self.inputs_applied.remove(address);
+        self.latencies.remove(address);
+        self.queues.remove(address);
+    }
+
+    // makes the client the authority for id, its events targeting id are accepted from now on
+    pub fn assign(&mut self, id: A::Id, address: SocketAddr) {
+        let previous = self.ownership.owner(&id);
+        self.ownership.assign(id, address);
+        if let Owner::Client(previous) = previous {
+            if previous != address {
+                self.send_ownership(previous);
+            }
+        }
+        self.send_ownership(address);
+    }
+
+    pub fn release(&mut self, id: &A::Id) {
+        if let Owner::Client(previous) = self.ownership.owner(id) {
+            self.ownership.release(id);
+            self.send_ownership(previous);
+        }
+    }
+
+    fn send_ownership(&mut self, address: SocketAddr) {
+        let owned = self.ownership.owned_by(&address);
+        let sent = self.clients.get(&address).map(|sender| sender.unbounded_send(ServerMessage::Ownership { owned }).is_ok());
+        if sent == Some(false) {
+            self.remove_client(&address);
+        }
+    }
+
+    // the server may change anything, owned or not
+    // overrides are routed after every client event received for the same step, so they win any conflict on the entity
+    pub fn override_event(&mut self, event: AppEvent<A>) {
+        self.overrides.push(event);
+    }
+
+    // what this client sees is decided by App::entity_visible/render_event_visible from the viewer's perspective
+    pub fn set_viewer(&mut self, address: SocketAddr, viewer: Option<A::Id>) {
+        match viewer {
+            Some(id) => self.viewers.insert(address, id),
+            None => self.viewers.remove(&address),
+        };
+    }
+
+    // each client gets the changes to what it can see since the last state it acknowledged
+    pub fn replicate(&mut self, render_events: &[A::RenderEvent]) {
+        let tick = self.simulation.tick;
+        self.replicator.record(tick, &self.simulation.entities);
+
+        let mut gone = Vec::new();
+        for (address, sender) in &self.clients {
+            let entities = &self.simulation.entities;
+            let (delta, events) = match self.viewers.get(address) {
+                Some(viewer) => {
+                    let delta = self.replicator.visible_delta_for(address, tick, entities, |id, e| A::entity_visible(viewer, entities, id, e));
+                    let events : Vec<A::RenderEvent> = render_events.iter().filter(|ev| A::render_event_visible(viewer, entities, ev)).cloned().collect();
+                    (delta, events)
+                },
+                None => (self.replicator.delta_for(address, tick, entities), render_events.to_vec()),
+            };
+
+            // inputs pushed before this step are reflected in its state
+            let mut sent = match self.inputs_applied.get(address) {
+                Some(&seq) => sender.unbounded_send(ServerMessage::InputApplied { seq }).is_ok(),
+                None => true,
+            };
+            sent = sent && sender.unbounded_send(ServerMessage::State(delta)).is_ok();
+            if sent && !events.is_empty() {
+                sent = sender.unbounded_send(ServerMessage::RenderEvents { tick, events }).is_ok();
+            }
+            if !sent {
+                gone.push(*address);
+            }
+        }
+        for address in gone {
+            self.remove_client(&address);
+        }
+    }
+
+    // poll, step the simulation once and send the result to every client
+    pub fn step(&mut self) -> Vec<A::RenderEvent> {
+        self.poll();
+        self.simulation.push_all(&mut self.overrides);
+
+        let render_events = self.simulation.step();
+        self.ownership.retain_existing(&self.simulation.entities);
+        self.replicate(&render_events);
+
+        render_events
+    }
+
+    // steps at the simulation's tick rate until a Shutdown is routed or the network side finishes
+    pub fn run(mut self) -> Simulation<A> {
+        let per_tick = Duration::new(0, self.simulation.per_tick_ns() as u32);
+        while self.simulation.running && !self.finished {
+            let started = Instant::now();
+            self.step();
+            let elapsed = started.elapsed();
+            if elapsed < per_tick {
+                thread::sleep(per_tick - elapsed);
+            }
+        }
+        self.shutdown()
+    }
+
+    pub fn shutdown(self) -> Simulation<A> {
+        if let Some(poison_pill) = self.poison_pill {
+            let _ = poison_pill.shutdown();
+        }
+        self.simulation
+    }
+}
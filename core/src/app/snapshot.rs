@@ -0,0 +1,293 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use {TreeMap, SimRng};
+use event::Event;
+use network::codec::{BincodeCodec, JsonCodec, SerializeCodec, DeserializeCodec, CodecError};
+use super::App;
+use super::ids::IdAllocator;
+use super::timers::TimerQueue;
+
+pub type SchemaVersion = u32;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    IO(io::Error),
+    Codec(CodecError),
+    Truncated,
+    UnsupportedSchemaVersion { found: SchemaVersion, expected: SchemaVersion },
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::IO(err)
+    }
+}
+
+impl From<CodecError> for SnapshotError {
+    fn from(err: CodecError) -> Self {
+        SnapshotError::Codec(err)
+    }
+}
+
+pub type SnapshotResult<T> = Result<T, SnapshotError>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SnapshotFormat {
+    Json,
+    Bincode,
+}
+
+impl SnapshotFormat {
+    pub fn encode<E>(&self, e: &E, bytes: &mut Vec<u8>) -> SnapshotResult<()> where E : Serialize {
+        match *self {
+            SnapshotFormat::Json => JsonCodec::serialize(e, bytes)?,
+            SnapshotFormat::Bincode => BincodeCodec::serialize(e, bytes)?,
+        }
+        Ok(())
+    }
+
+    pub fn decode<E>(&self, bytes: &[u8]) -> SnapshotResult<E> where E : DeserializeOwned {
+        let e = match *self {
+            SnapshotFormat::Json => JsonCodec::deserialize(bytes)?,
+            SnapshotFormat::Bincode => BincodeCodec::deserialize(bytes)?,
+        };
+        Ok(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub schema_version: SchemaVersion, // App::schema_version() at the time of saving
+    pub tick: u64,
+    pub rng: SimRng,
+    pub tick_rate: u64,
+}
+
+// an event and the entity that routed it, None for events from outside the simulation
+pub type Routed<Id, E> = (Option<Id>, E);
+
+// the part of a snapshot whose shape depends on the App, this is what migrations operate on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "Id: Ord + Deserialize<'de>, Entity: Deserialize<'de>, EntityEvent: Deserialize<'de>, RenderEvent: Deserialize<'de>, IdKind: Ord + Deserialize<'de>"))]
+pub struct SnapshotBody<Id, Entity, EntityEvent, RenderEvent, IdKind> where Id : Ord, IdKind : Ord {
+    #[serde(with = "as_pairs")]
+    pub entities: TreeMap<Id, Entity>,
+    pub pending: Vec<Routed<Id, Event<Id, Entity, EntityEvent, RenderEvent>>>, // routed by the simulation to itself (with the routing entity), not yet delivered
+    pub ids: IdAllocator<IdKind>,
+    pub timers: TimerQueue<Id, EntityEvent>,
+}
+
+// json only allows string keys, so maps keyed by ids are written as a list of pairs
+pub mod as_pairs {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use TreeMap;
+
+    pub fn serialize<K, V, S>(map: &TreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> where K : Serialize + Ord, V : Serialize, S : Serializer {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<TreeMap<K, V>, D::Error> where K : Deserialize<'de> + Ord, V : Deserialize<'de>, D : Deserializer<'de> {
+        let pairs : Vec<(K, V)> = Vec::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+pub type AppSnapshotBody<A> = SnapshotBody<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent, <A as App>::IdKind>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "Id: Ord + Deserialize<'de>, Entity: Deserialize<'de>, EntityEvent: Deserialize<'de>, RenderEvent: Deserialize<'de>, IdKind: Ord + Deserialize<'de>"))]
+pub struct Snapshot<Id, Entity, EntityEvent, RenderEvent, IdKind> where Id : Ord, IdKind : Ord {
+    pub header: SnapshotHeader,
+    pub body: SnapshotBody<Id, Entity, EntityEvent, RenderEvent, IdKind>,
+}
+
+pub type AppSnapshot<A> = Snapshot<<A as App>::Id, <A as App>::Entity, <A as App>::EntityEvent, <A as App>::RenderEvent, <A as App>::IdKind>;
+
+// on disk: a big endian u32 header length, the encoded header, then the encoded body
+pub fn write_snapshot<A, W>(snapshot: &AppSnapshot<A>, format: SnapshotFormat, writer: &mut W) -> SnapshotResult<()> where A : App, W : Write {
+    let mut header_bytes = Vec::new();
+    format.encode(&snapshot.header, &mut header_bytes)?;
+    let mut body_bytes = Vec::new();
+    format.encode(&snapshot.body, &mut body_bytes)?;
+
+    let len = header_bytes.len() as u32;
+    writer.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    writer.write_all(&header_bytes)?;
+    writer.write_all(&body_bytes)?;
+    Ok(())
+}
+
+// reads a snapshot, handing older schema versions to App::migrate_snapshot
+pub fn read_snapshot<A, R>(format: SnapshotFormat, reader: &mut R) -> SnapshotResult<AppSnapshot<A>> where A : App, R : Read {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 4 {
+        return Err(SnapshotError::Truncated);
+    }
+    let len = ((bytes[0] as usize) << 24) | ((bytes[1] as usize) << 16) | ((bytes[2] as usize) << 8) | (bytes[3] as usize);
+    if bytes.len() < 4 + len {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let mut header : SnapshotHeader = format.decode(&bytes[4..(4 + len)])?;
+    let body_bytes = &bytes[(4 + len)..];
+
+    let body = if header.schema_version == A::schema_version() {
+        format.decode(body_bytes)?
+    } else {
+        let migrated = A::migrate_snapshot(&header, format, body_bytes)?;
+        header.schema_version = A::schema_version();
+        migrated
+    };
+
+    Ok(Snapshot {
+        header,
+        body,
+    })
+}
+
+pub fn save_snapshot<A>(snapshot: &AppSnapshot<A>, format: SnapshotFormat, path: &Path) -> SnapshotResult<()> where A : App {
+    let mut file = File::create(path)?;
+    write_snapshot::<A, File>(snapshot, format, &mut file)
+}
+
+pub fn load_snapshot<A>(format: SnapshotFormat, path: &Path) -> SnapshotResult<AppSnapshot<A>> where A : App {
+    let mut file = File::open(path)?;
+    read_snapshot::<A, File>(format, &mut file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Tick;
+    use event::{Sink, CombinedSink};
+    use app::{SimSettings, Simulation};
+    use app::spatial::World;
+    use app::test_app::{self, TestApp, TestId, TestKind, TestEvent, Counter, counters, inputs};
+
+    // TestApp a schema version later, where loading an old snapshot offsets every value
+    struct TestAppV1;
+
+    impl App for TestAppV1 {
+        type Id = TestId;
+        type Entity = Counter;
+        type EntityEvent = TestEvent;
+        type RenderEvent = i64;
+        type IdKind = TestKind;
+
+        fn id_for(kind: TestKind, n: u64) -> TestId {
+            TestApp::id_for(kind, n)
+        }
+
+        fn id_kind(id: &TestId) -> Option<(TestKind, u64)> {
+            TestApp::id_kind(id)
+        }
+
+        fn handle_entity_event(event: &TestEvent, id: &TestId, entity: &mut Counter, sink: &mut Sink<test_app::AppEvent, TestKind>) {
+            TestApp::handle_entity_event(event, id, entity, sink)
+        }
+
+        fn simulate(time: Tick, entities: &World<TestId, Counter>, id: &TestId, entity: &Counter, sink: &mut CombinedSink<TestEvent, test_app::AppEvent, TestKind>) {
+            TestApp::simulate(time, entities, id, entity, sink)
+        }
+
+        fn schema_version() -> SchemaVersion {
+            1
+        }
+
+        fn migrate_snapshot(header: &SnapshotHeader, format: SnapshotFormat, body: &[u8]) -> SnapshotResult<AppSnapshotBody<Self>> {
+            assert_eq!(header.schema_version, 0);
+            let mut body : AppSnapshotBody<TestApp> = format.decode(body)?;
+            for entity in body.entities.values_mut() {
+                entity.value += 1000;
+            }
+            Ok(body)
+        }
+    }
+
+    fn stepped(ticks: u64) -> AppSnapshot<TestApp> {
+        let mut simulation = counters(5).with_seed(3);
+        for n in 0..ticks {
+            simulation.push_all(&mut inputs(n));
+            simulation.step();
+        }
+        simulation.snapshot()
+    }
+
+    fn round_trip(format: SnapshotFormat) {
+        let snapshot = stepped(12);
+        let mut bytes = Vec::new();
+        write_snapshot::<TestApp, _>(&snapshot, format, &mut bytes).unwrap();
+        let loaded = read_snapshot::<TestApp, _>(format, &mut &bytes[..]).unwrap();
+
+        assert_eq!(loaded.header, snapshot.header);
+        assert_eq!(loaded.body.entities, snapshot.body.entities);
+        assert_eq!(loaded.body.ids, snapshot.body.ids);
+        assert_eq!(loaded.body.timers.len(), snapshot.body.timers.len());
+        assert_eq!(loaded.body.pending.len(), snapshot.body.pending.len());
+
+        // carries on exactly as the original would have
+        let mut original = Simulation::<TestApp>::from_snapshot(snapshot);
+        let mut restored = Simulation::<TestApp>::from_snapshot(loaded);
+        for n in 12..30 {
+            original.push_all(&mut inputs(n));
+            restored.push_all(&mut inputs(n));
+            original.step();
+            restored.step();
+            assert_eq!(original.checksum(), restored.checksum());
+        }
+    }
+
+    #[test]
+    fn round_trips_json() {
+        round_trip(SnapshotFormat::Json);
+    }
+
+    #[test]
+    fn round_trips_bincode() {
+        round_trip(SnapshotFormat::Bincode);
+    }
+
+    #[test]
+    fn migrates_older_schema_versions() {
+        let snapshot = stepped(6);
+        let mut bytes = Vec::new();
+        write_snapshot::<TestApp, _>(&snapshot, SnapshotFormat::Bincode, &mut bytes).unwrap();
+
+        let migrated = read_snapshot::<TestAppV1, _>(SnapshotFormat::Bincode, &mut &bytes[..]).unwrap();
+        assert_eq!(migrated.header.schema_version, 1);
+        assert_eq!(migrated.header.tick, snapshot.header.tick);
+        for (id, entity) in &snapshot.body.entities {
+            assert_eq!(migrated.body.entities[id].value, entity.value + 1000);
+        }
+    }
+
+    #[test]
+    fn rejects_versions_without_a_migration() {
+        let snapshot : AppSnapshot<TestAppV1> = Simulation::<TestAppV1>::new(SimSettings { tick_rate: 60 }, TreeMap::new()).snapshot();
+        let mut bytes = Vec::new();
+        write_snapshot::<TestAppV1, _>(&snapshot, SnapshotFormat::Json, &mut bytes).unwrap();
+        match read_snapshot::<TestApp, _>(SnapshotFormat::Json, &mut &bytes[..]) {
+            Err(SnapshotError::UnsupportedSchemaVersion { found: 1, expected: 0 }) => (),
+            other => panic!("expected an unsupported version, got {:?}", other.map(|s| s.header)),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_snapshots() {
+        let snapshot = stepped(2);
+        let mut bytes = Vec::new();
+        write_snapshot::<TestApp, _>(&snapshot, SnapshotFormat::Bincode, &mut bytes).unwrap();
+        match read_snapshot::<TestApp, _>(SnapshotFormat::Bincode, &mut &bytes[..6]) {
+            Err(SnapshotError::Truncated) => (),
+            other => panic!("expected truncated, got {:?}", other.map(|s| s.header)),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::ops::Deref;
+
+use {Vec2, HashMap, TreeMap};
+use math::Rect;
+
+// a circle in simulation space, what App::locate returns for entities taking part in spatial queries
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    pub centre: Vec2,
+    pub radius: f64,
+}
+
+impl Bounds {
+    pub fn intersects_circle(&self, centre: Vec2, radius: f64) -> bool {
+        let dx = self.centre.x - centre.x;
+        let dy = self.centre.y - centre.y;
+        let r = self.radius + radius;
+        dx * dx + dy * dy <= r * r
+    }
+
+    pub fn intersects_rect(&self, rect: &Rect<f64>) -> bool {
+        let nx = self.centre.x.max(rect.min.x).min(rect.max.x);
+        let ny = self.centre.y.max(rect.min.y).min(rect.max.y);
+        let dx = self.centre.x - nx;
+        let dy = self.centre.y - ny;
+        dx * dx + dy * dy <= self.radius * self.radius
+    }
+}
+
+pub type Cell = (i64, i64);
+
+// entities spanning more cells than this (per axis) aren't entered into cells, every query checks them instead
+pub const MAX_SPAN_CELLS : i64 = 64;
+
+// uniform grid rebuilt every step, entities are entered into every cell their bounds overlap
+pub struct SpatialGrid<Id> {
+    pub cell_size: f64,
+    entries: Vec<(Id, Bounds)>, // in id order
+    cells: HashMap<Cell, Vec<usize>>,
+    oversized: Vec<usize>,
+    occupied: Option<(Cell, Cell)>, // the span of every cell holding something, queries are clamped to it
+}
+
+impl<Id> SpatialGrid<Id> where Id : Ord + Clone {
+    pub fn new(cell_size: f64) -> SpatialGrid<Id> {
+        SpatialGrid {
+            cell_size,
+            entries: Vec::new(),
+            cells: HashMap::default(),
+            oversized: Vec::new(),
+            occupied: None,
+        }
+    }
+
+    pub fn build<Entity, F>(cell_size: f64, entities: &TreeMap<Id, Entity>, locate: F) -> SpatialGrid<Id> where F : Fn(&Entity) -> Option<Bounds> {
+        let mut grid = SpatialGrid::new(cell_size);
+        for (id, entity) in entities {
+            if let Some(bounds) = locate(entity) {
+                grid.insert(id.clone(), bounds);
+            }
+        }
+        grid
+    }
+
+    // ids must be inserted in ascending order, query results keep that order
+    // bounds that aren't finite (or have a negative radius) are ignored, they'd never match a query
+    pub fn insert(&mut self, id: Id, bounds: Bounds) {
+        if !(bounds.centre.x.is_finite() && bounds.centre.y.is_finite() && bounds.radius.is_finite() && bounds.radius >= 0.0) {
+            return;
+        }
+        let index = self.entries.len();
+        let (min, max) = self.cell_span(bounds.centre.x - bounds.radius, bounds.centre.y - bounds.radius, bounds.centre.x + bounds.radius, bounds.centre.y + bounds.radius);
+        if max.0.saturating_sub(min.0) >= MAX_SPAN_CELLS || max.1.saturating_sub(min.1) >= MAX_SPAN_CELLS {
+            self.oversized.push(index);
+        } else {
+            for cx in min.0..=max.0 {
+                for cy in min.1..=max.1 {
+                    self.cells.entry((cx, cy)).or_default().push(index);
+                }
+            }
+            self.occupied = Some(match self.occupied {
+                Some((omin, omax)) => ((omin.0.min(min.0), omin.1.min(min.1)), (omax.0.max(max.0), omax.1.max(max.1))),
+                None => (min, max),
+            });
+        }
+        self.entries.push((id, bounds));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bounds(&self) -> &[(Id, Bounds)] {
+        &self.entries
+    }
+
+    pub fn within_radius(&self, centre: Vec2, radius: f64) -> Vec<(&Id, &Bounds)> {
+        if !(centre.x.is_finite() && centre.y.is_finite() && radius.is_finite()) {
+            return Vec::new();
+        }
+        let (min, max) = self.cell_span(centre.x - radius, centre.y - radius, centre.x + radius, centre.y + radius);
+        self.candidates(min, max).into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|(_, bounds)| bounds.intersects_circle(centre, radius))
+            .map(|(id, bounds)| (id, bounds))
+            .collect()
+    }
+
+    pub fn within_rect(&self, rect: &Rect<f64>) -> Vec<(&Id, &Bounds)> {
+        if !(rect.min.x.is_finite() && rect.min.y.is_finite() && rect.max.x.is_finite() && rect.max.y.is_finite()) {
+            return Vec::new();
+        }
+        let (min, max) = self.cell_span(rect.min.x, rect.min.y, rect.max.x, rect.max.y);
+        self.candidates(min, max).into_iter()
+            .map(|i| &self.entries[i])
+            .filter(|(_, bounds)| bounds.intersects_rect(rect))
+            .map(|(id, bounds)| (id, bounds))
+            .collect()
+    }
+
+    fn cell_of(&self, x: f64, y: f64) -> Cell {
+        ((x / self.cell_size).floor() as i64, (y / self.cell_size).floor() as i64)
+    }
+
+    fn cell_span(&self, min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> (Cell, Cell) {
+        (self.cell_of(min_x, min_y), self.cell_of(max_x, max_y))
+    }
+
+    // the oversized entries and everything in the cells between min and max, never visiting more cells than are occupied
+    fn candidates(&self, min: Cell, max: Cell) -> Vec<usize> {
+        let mut found = self.oversized.clone();
+        if let Some((omin, omax)) = self.occupied {
+            let (min_x, min_y) = (min.0.max(omin.0), min.1.max(omin.1));
+            let (max_x, max_y) = (max.0.min(omax.0), max.1.min(omax.1));
+            if min_x <= max_x && min_y <= max_y {
+                let span = (max_x - min_x + 1).saturating_mul(max_y - min_y + 1);
+                if span as u64 > self.cells.len() as u64 {
+                    // sparse, walking what's occupied is cheaper than the span
+                    for (&(cx, cy), indices) in &self.cells {
+                        if cx >= min_x && cx <= max_x && cy >= min_y && cy <= max_y {
+                            found.extend_from_slice(indices);
+                        }
+                    }
+                } else {
+                    for cx in min_x..=max_x {
+                        for cy in min_y..=max_y {
+                            if let Some(indices) = self.cells.get(&(cx, cy)) {
+                                found.extend_from_slice(indices);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        found
+    }
+}
+
+// what simulate sees, derefs to the entity map so it reads like one
+pub struct World<'a, Id, Entity> where Id : 'a + Ord, Entity : 'a {
+    pub entities: &'a TreeMap<Id, Entity>,
+    pub index: &'a SpatialGrid<Id>,
+}
+
+impl<'a, Id, Entity> Deref for World<'a, Id, Entity> where Id : 'a + Ord, Entity : 'a {
+    type Target = TreeMap<Id, Entity>;
+
+    fn deref(&self) -> &TreeMap<Id, Entity> {
+        self.entities
+    }
+}
+
+impl<'a, Id, Entity> World<'a, Id, Entity> where Id : 'a + Ord + Clone, Entity : 'a {
+    // located entities whose bounds overlap the circle, in id order
+    pub fn within_radius(&self, centre: Vec2, radius: f64) -> Vec<(&'a Id, &'a Entity)> {
+        let (entities, index) = (self.entities, self.index);
+        index.within_radius(centre, radius).into_iter().filter_map(|(id, _)| entities.get(id).map(|e| (id, e))).collect()
+    }
+
+    // located entities whose bounds overlap the rect, in id order
+    pub fn within_rect(&self, rect: &Rect<f64>) -> Vec<(&'a Id, &'a Entity)> {
+        let (entities, index) = (self.entities, self.index);
+        index.within_rect(rect).into_iter().filter_map(|(id, _)| entities.get(id).map(|e| (id, e))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64;
+    use cgmath::vec2;
+
+    fn at(x: f64, y: f64, radius: f64) -> Bounds {
+        Bounds { centre: vec2(x, y), radius }
+    }
+
+    fn ids(found: Vec<(&u32, &Bounds)>) -> Vec<u32> {
+        found.into_iter().map(|(id, _)| *id).collect()
+    }
+
+    fn grid() -> SpatialGrid<u32> {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, at(0.0, 0.0, 1.0));
+        grid.insert(2, at(15.0, 0.0, 1.0));
+        grid.insert(3, at(-40.0, 25.0, 6.0));
+        grid.insert(4, at(1000.0, 1000.0, 1.0));
+        grid
+    }
+
+    #[test]
+    fn finds_overlapping_bounds_in_id_order() {
+        let grid = grid();
+        assert_eq!(ids(grid.within_radius(vec2(8.0, 0.0), 7.5)), vec![1, 2]);
+        assert_eq!(ids(grid.within_radius(vec2(-33.0, 25.0), 1.5)), vec![3]);
+        assert_eq!(ids(grid.within_rect(&Rect { min: vec2(-50.0, -5.0), max: vec2(20.0, 30.0) })), vec![1, 2, 3]);
+        assert!(grid.within_radius(vec2(500.0, 500.0), 5.0).is_empty());
+    }
+
+    #[test]
+    fn huge_queries_are_clamped_to_what_is_occupied() {
+        let grid = grid();
+        assert_eq!(ids(grid.within_radius(vec2(0.0, 0.0), 1e300)), vec![1, 2, 3, 4]);
+        assert_eq!(ids(grid.within_rect(&Rect { min: vec2(-1e300, -1e300), max: vec2(1e300, 1e300) })), vec![1, 2, 3, 4]);
+        assert_eq!(ids(grid.within_radius(vec2(0.0, 0.0), f64::MAX)), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn non_finite_queries_find_nothing() {
+        let grid = grid();
+        assert!(grid.within_radius(vec2(0.0, 0.0), f64::NAN).is_empty());
+        assert!(grid.within_radius(vec2(f64::INFINITY, 0.0), 5.0).is_empty());
+        assert!(grid.within_radius(vec2(0.0, 0.0), f64::INFINITY).is_empty());
+        assert!(grid.within_rect(&Rect { min: vec2(f64::NEG_INFINITY, 0.0), max: vec2(0.0, 0.0) }).is_empty());
+    }
+
+    #[test]
+    fn degenerate_and_oversized_bounds() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, at(f64::NAN, 0.0, 1.0));
+        grid.insert(2, at(0.0, 0.0, f64::INFINITY));
+        grid.insert(3, at(0.0, 0.0, 1e12)); // too big for cells, checked by every query
+        grid.insert(4, at(1e300, -1e300, 1.0)); // cells saturate, but it's still found
+        assert_eq!(grid.len(), 2);
+        assert_eq!(ids(grid.within_radius(vec2(5e11, 0.0), 1.0)), vec![3]);
+        assert_eq!(ids(grid.within_radius(vec2(1e300, -1e300), 2.0)), vec![4]);
+    }
+}
@@ -0,0 +1,122 @@
+// a small app for the tests, counters that drift with the rng, throw off sparks and ping each other on timers
+
+use {Tick, Vec2, TreeMap};
+use event::*;
+use super::{App, SimSettings, Simulation};
+use super::spatial::{Bounds, World};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TestId {
+    Counter(u64),
+    Spark(u64),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TestKind {
+    Counter,
+    Spark,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Counter {
+    pub value: i64,
+    pub x: f64,
+    pub ttl: u32, // sparks delete themselves when this runs out, counters have 0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TestEvent {
+    Add(i64),
+    Ping(u64), // throws off a spark as well
+    Fade,
+}
+
+pub struct TestApp;
+
+impl App for TestApp {
+    type Id = TestId;
+    type Entity = Counter;
+    type EntityEvent = TestEvent;
+    type RenderEvent = i64;
+    type IdKind = TestKind;
+
+    fn id_for(kind: TestKind, n: u64) -> TestId {
+        match kind {
+            TestKind::Counter => TestId::Counter(n),
+            TestKind::Spark => TestId::Spark(n),
+        }
+    }
+
+    fn id_kind(id: &TestId) -> Option<(TestKind, u64)> {
+        match *id {
+            TestId::Counter(n) => Some((TestKind::Counter, n)),
+            TestId::Spark(n) => Some((TestKind::Spark, n)),
+        }
+    }
+
+    fn handle_entity_event(event: &TestEvent, _id: &TestId, entity: &mut Counter, sink: &mut Sink<AppEvent, TestKind>) {
+        match *event {
+            TestEvent::Add(n) => entity.value += n,
+            TestEvent::Ping(n) => {
+                entity.value += n as i64;
+                sink.push(Event::RenderEvent(entity.value));
+                let spark = sink.ids.next::<TestApp>(TestKind::Spark);
+                sink.push(Event::SpawnEvent(spark, Counter { value: 0, x: entity.x, ttl: 3 }));
+            },
+            TestEvent::Fade => entity.ttl -= 1,
+        }
+    }
+
+    fn simulate(_time: Tick, entities: &World<TestId, Counter>, id: &TestId, entity: &Counter, sink: &mut CombinedSink<TestEvent, AppEvent, TestKind>) {
+        match *id {
+            TestId::Counter(_) => {
+                let drift = (sink.rng.next_u64() % 3) as i64 - 1;
+                sink.mine.push(TestEvent::Add(drift));
+                let neighbours = entities.within_radius(Vec2::new(entity.x, 0.0), 10.0).len() as i64;
+                sink.mine.push(TestEvent::Add(neighbours));
+                if sink.rng.next_u64() % 4 == 1 {
+                    let spark = sink.ids.next::<TestApp>(TestKind::Spark);
+                    sink.routed.push(Event::SpawnEvent(spark, Counter { value: 0, x: entity.x, ttl: 3 }));
+                }
+            },
+            TestId::Spark(_) => {
+                if entity.ttl <= 1 {
+                    sink.routed.push(Event::Delete(*id));
+                } else {
+                    sink.mine.push(TestEvent::Fade);
+                }
+            },
+        }
+    }
+
+    fn locate(entity: &Counter) -> Option<Bounds> {
+        Some(Bounds { centre: Vec2::new(entity.x, 0.0), radius: 1.0 })
+    }
+
+    fn spatial_cell_size() -> f64 {
+        16.0
+    }
+}
+
+pub type AppEvent = super::AppEvent<TestApp>;
+
+// n counters spread along the x axis
+pub fn counters(n: u64) -> Simulation<TestApp> {
+    let mut entities = TreeMap::new();
+    for i in 0..n {
+        entities.insert(TestId::Counter(i), Counter { value: 0, x: i as f64 * 4.0, ttl: 0 });
+    }
+    Simulation::new(SimSettings { tick_rate: 60 }, entities)
+}
+
+// the same input every run, something for every few ticks
+pub fn inputs(tick: u64) -> Vec<AppEvent> {
+    let mut events = Vec::new();
+    if tick % 3 == 1 {
+        events.push(Event::EntityEvent(TestId::Counter(tick % 4), TestEvent::Add(tick as i64)));
+    }
+    if tick % 5 == 2 {
+        events.push(Event::ScheduleEvent(TimerId(1000 + tick), Delivery::InTicks(2), TestId::Counter(0), TestEvent::Ping(tick)));
+    }
+    events
+}
@@ -0,0 +1,158 @@
+use std::collections::Bound::Included;
+
+use TreeMap;
+use event::{TimerId, Delivery};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timer<Id, EntityEvent> {
+    pub due: u64, // the tick the event is handled on
+    pub target: Id,
+    pub event: EntityEvent,
+    pub origin: Option<Id>, // the entity that scheduled it
+}
+
+// scheduled entity events, owned by the simulation and saved with its snapshots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerQueue<Id, EntityEvent> {
+    pub timers: TreeMap<TimerId, Timer<Id, EntityEvent>>,
+    pub by_due: TreeMap<u64, Vec<TimerId>>, // may still list cancelled timers, they're skipped when popped
+}
+
+impl<Id, EntityEvent> Default for TimerQueue<Id, EntityEvent> {
+    fn default() -> TimerQueue<Id, EntityEvent> {
+        TimerQueue::new()
+    }
+}
+
+impl<Id, EntityEvent> TimerQueue<Id, EntityEvent> {
+    pub fn new() -> TimerQueue<Id, EntityEvent> {
+        TimerQueue {
+            timers: TreeMap::new(),
+            by_due: TreeMap::new(),
+        }
+    }
+
+    // routed on tick n, so "in 1 tick" means handled on n, like any other event routed from the last tick
+    pub fn due_tick(delivery: Delivery, n: u64) -> u64 {
+        match delivery {
+            Delivery::InTicks(ticks) => (n + ticks).saturating_sub(1).max(n),
+            Delivery::AtTick(at) => at.max(n),
+        }
+    }
+
+    // false if the id is already live, the existing timer is kept and this one dropped
+    pub fn schedule(&mut self, timer_id: TimerId, due: u64, target: Id, event: EntityEvent, origin: Option<Id>) -> bool {
+        if self.timers.contains_key(&timer_id) {
+            return false;
+        }
+        self.timers.insert(timer_id, Timer { due, target, event, origin });
+        self.by_due.entry(due).or_default().push(timer_id);
+        true
+    }
+
+    pub fn cancel(&mut self, timer_id: TimerId) -> Option<Timer<Id, EntityEvent>> {
+        self.timers.remove(&timer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    // every live timer due on or before tick n, ordered by due tick then timer id
+    pub fn pop_due(&mut self, n: u64) -> Vec<(TimerId, Timer<Id, EntityEvent>)> {
+        let due_ticks : Vec<u64> = self.by_due.range((Included(0), Included(n))).map(|(k, _)| *k).collect();
+        let mut out = Vec::new();
+        for due in due_ticks {
+            let mut timer_ids = self.by_due.remove(&due).unwrap_or_default();
+            timer_ids.sort();
+            for timer_id in timer_ids {
+                let is_due = self.timers.get(&timer_id).map(|t| t.due == due).unwrap_or(false); // ids cancelled and scheduled again live on under their new due tick
+                if is_due {
+                    if let Some(timer) = self.timers.remove(&timer_id) {
+                        out.push((timer_id, timer));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::Event;
+    use app::DiagnosticsMode;
+    use app::test_app::{TestId, TestEvent, counters};
+
+    #[test]
+    fn pops_by_due_tick_then_timer_id() {
+        let mut timers : TimerQueue<u32, &str> = TimerQueue::new();
+        timers.schedule(TimerId(5), 3, 1, "c", None);
+        timers.schedule(TimerId(9), 2, 1, "a", None);
+        timers.schedule(TimerId(2), 3, 1, "b", None);
+        timers.schedule(TimerId(1), 7, 1, "later", None);
+
+        assert!(timers.pop_due(1).is_empty());
+        let popped : Vec<(TimerId, &str)> = timers.pop_due(4).into_iter().map(|(id, t)| (id, t.event)).collect();
+        assert_eq!(popped, vec![(TimerId(9), "a"), (TimerId(2), "b"), (TimerId(5), "c")]);
+        assert_eq!(timers.len(), 1);
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut timers : TimerQueue<u32, &str> = TimerQueue::new();
+        timers.schedule(TimerId(1), 2, 1, "kept", None);
+        timers.schedule(TimerId(2), 2, 1, "cancelled", None);
+        assert_eq!(timers.cancel(TimerId(2)).map(|t| t.event), Some("cancelled"));
+        assert!(timers.cancel(TimerId(2)).is_none());
+
+        let popped : Vec<&str> = timers.pop_due(10).into_iter().map(|(_, t)| t.event).collect();
+        assert_eq!(popped, vec!["kept"]);
+        assert!(timers.is_empty());
+    }
+
+    #[test]
+    fn live_ids_are_never_replaced() {
+        let mut timers : TimerQueue<u32, &str> = TimerQueue::new();
+        assert!(timers.schedule(TimerId(1), 2, 1, "first", None));
+        assert!(!timers.schedule(TimerId(1), 1, 1, "second", None));
+        assert!(timers.pop_due(1).is_empty());
+
+        timers.cancel(TimerId(1));
+        assert!(timers.schedule(TimerId(1), 3, 1, "again", None));
+        let popped : Vec<&str> = timers.pop_due(10).into_iter().map(|(_, t)| t.event).collect();
+        assert_eq!(popped, vec!["again"]);
+    }
+
+    #[test]
+    fn due_ticks_count_from_the_routing_tick() {
+        assert_eq!(TimerQueue::<u32, ()>::due_tick(Delivery::InTicks(1), 10), 10);
+        assert_eq!(TimerQueue::<u32, ()>::due_tick(Delivery::InTicks(3), 10), 12);
+        assert_eq!(TimerQueue::<u32, ()>::due_tick(Delivery::AtTick(4), 10), 10);
+        assert_eq!(TimerQueue::<u32, ()>::due_tick(Delivery::AtTick(15), 10), 15);
+    }
+
+    #[test]
+    fn simulations_fire_and_cancel_scheduled_events() {
+        let mut simulation = counters(2).with_diagnostics(DiagnosticsMode::Collect);
+        simulation.push(Event::ScheduleEvent(TimerId(100), Delivery::InTicks(3), TestId::Counter(1), TestEvent::Ping(7)));
+        simulation.push(Event::ScheduleEvent(TimerId(101), Delivery::InTicks(2), TestId::Counter(1), TestEvent::Ping(5)));
+        simulation.push(Event::ScheduleEvent(TimerId(102), Delivery::InTicks(2), TestId::Counter(0), TestEvent::Ping(1)));
+        simulation.step();
+        assert_eq!(simulation.timers.len(), 3);
+
+        simulation.push(Event::CancelEvent(TimerId(102)));
+        assert!(simulation.step().is_empty()); // 101 is handled on tick 1, 102 never is
+        assert_eq!(simulation.timers.len(), 1);
+        assert_eq!(simulation.step().len(), 1); // 101's render event, 100 handled on tick 2
+        assert!(simulation.timers.is_empty());
+        assert_eq!(simulation.step().len(), 1); // 100's
+        assert!(simulation.step().is_empty());
+        assert_eq!(simulation.diagnostics.drain(), vec![]);
+    }
+}
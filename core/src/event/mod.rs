@@ -1,5 +1,15 @@
+use SimRng;
+use app::ids::IdLease;
 
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TimerId(pub u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Delivery {
+    InTicks(u64), // 1 is the same as an unscheduled event, delivered on the next tick
+    AtTick(u64), // ticks already passed are delivered as soon as possible
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum Event<Id, Entity, EntityEvent, RenderEvent>  {
@@ -9,17 +19,31 @@ pub enum Event<Id, Entity, EntityEvent, RenderEvent>  {
     DeleteRange(Id, Id),
     EntityEvent(Id, EntityEvent),
     RenderEvent(RenderEvent),
+    ScheduleEvent(TimerId, Delivery, Id, EntityEvent),
+    CancelEvent(TimerId),
 }
 
 
-pub struct Sink<A> {
+pub struct Sink<A, K = ()> where K : Ord {
     pub events: Vec<A>,
+    pub rng: SimRng, // deterministic when handed out by the simulation
+    pub ids: IdLease<K>, // reserve ids for entities spawned through events, detached outside of a simulation step
 }
 
-impl<A> Sink<A> {
-    pub fn empty() -> Sink<A> {
+impl<A, K> Sink<A, K> where K : Ord + Clone {
+    pub fn empty() -> Sink<A, K> {
+        Sink::with_rng(SimRng::seeded(0))
+    }
+
+    pub fn with_rng(rng: SimRng) -> Sink<A, K> {
+        Sink::with(rng, IdLease::detached())
+    }
+
+    pub fn with(rng: SimRng, ids: IdLease<K>) -> Sink<A, K> {
         Sink {
-            events: Vec::new()
+            events: Vec::new(),
+            rng,
+            ids,
         }
     }
 
@@ -39,21 +63,71 @@ impl<A> Sink<A> {
     }
 }
 
-pub struct CombinedSink<A, B> {
+impl<EntityEvent, Id, Entity, RenderEvent, K> Sink<Event<Id, Entity, EntityEvent, RenderEvent>, K> where K : Ord + Clone {
+    // as CombinedSink::schedule
+    pub fn schedule(&mut self, delivery: Delivery, id: Id, event: EntityEvent) -> TimerId {
+        let timer_id = self.ids.reserve_timer();
+        self.push(Event::ScheduleEvent(timer_id, delivery, id, event));
+        timer_id
+    }
+
+    pub fn cancel(&mut self, timer_id: TimerId) {
+        self.push(Event::CancelEvent(timer_id));
+    }
+}
+
+pub struct CombinedSink<A, B, K> where K : Ord {
     pub mine: Sink<A>,
     pub routed: Sink<B>,
+    pub rng: SimRng,
+    pub ids: IdLease<K>, // reserve ids for entities spawned through routed
 }
 
-impl<A, B> CombinedSink<A, B> {
+impl<A, B, K> CombinedSink<A, B, K> where K : Ord + Clone {
     pub fn clear(&mut self) {
         self.mine.clear();
         self.routed.clear();
     }
 
-    pub fn empty() -> CombinedSink<A, B> {
+    pub fn empty() -> CombinedSink<A, B, K> {
+        CombinedSink::with(SimRng::seeded(0), IdLease::detached())
+    }
+
+    // mine and routed get forks of rng, so their streams differ from it and each other but stay deterministic
+    pub fn with(rng: SimRng, ids: IdLease<K>) -> CombinedSink<A, B, K> {
         CombinedSink  {
-            mine: Sink::empty(),
-            routed: Sink::empty(),
+            mine: Sink::with_rng(rng.fork(1)),
+            routed: Sink::with_rng(rng.fork(2)),
+            rng,
+            ids,
         }
     }
-}
\ No newline at end of file
+}
+
+impl<EntityEvent, Id, Entity, RenderEvent, K> CombinedSink<EntityEvent, Event<Id, Entity, EntityEvent, RenderEvent>, K> where K : Ord + Clone {
+    // deliver an event later, the returned id can be used to cancel it
+    pub fn schedule(&mut self, delivery: Delivery, id: Id, event: EntityEvent) -> TimerId {
+        let timer_id = self.ids.reserve_timer();
+        self.routed.push(Event::ScheduleEvent(timer_id, delivery, id, event));
+        timer_id
+    }
+
+    pub fn cancel(&mut self, timer_id: TimerId) {
+        self.routed.push(Event::CancelEvent(timer_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_sinks_fork_their_rng() {
+        let mut a : CombinedSink<u8, u8, u8> = CombinedSink::with(SimRng::seeded(5), IdLease::detached());
+        let mut b : CombinedSink<u8, u8, u8> = CombinedSink::with(SimRng::seeded(6), IdLease::detached());
+        let drawn = [a.rng.next_u64(), a.mine.rng.next_u64(), a.routed.rng.next_u64()];
+        assert!(drawn[0] != drawn[1] && drawn[1] != drawn[2] && drawn[0] != drawn[2]);
+        assert!(a.mine.rng.next_u64() != b.mine.rng.next_u64());
+        assert!(a.routed.rng.next_u64() != b.routed.rng.next_u64());
+    }
+}
@@ -14,15 +14,21 @@ extern crate cgmath;
 
 extern crate fnv;
 
+extern crate rand;
+
 extern crate rayon;
 
+extern crate multimap;
+
 pub mod event;
 pub mod network;
 pub mod color;
 pub mod math;
 pub mod app;
+pub mod rng;
 
 pub use color::*;
+pub use rng::SimRng;
 
 pub type Vec2 = cgmath::Vector2<f64>;
 pub type Vec2f = cgmath::Vector2<f32>;
@@ -1,5 +1,5 @@
 
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle, Timeout};
 use tokio_core::net::{TcpStream};
 
 use serde::{Serialize};
@@ -7,20 +7,29 @@ use serde::de::DeserializeOwned;
 
 use std::fmt::Debug;
 use std::net::SocketAddr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io;
 
 use futures;
 use futures::sync::mpsc::{UnboundedSender};
 use futures::sync::oneshot;
-use futures::{Future, Stream, Sink};
+use futures::{future, Future, Stream, Sink};
 
 use std::sync::mpsc::{Sender};
 use std::thread;
 
 use bytes::{BytesMut};
 
-use super::codec::AsymmetricCodec;
+use super::codec::{AsymmetricCodec, deserialize_bincode};
+use super::handshake::{Handshake, HandshakeReply, handshake_frame, handshake_within};
+use super::heartbeat::{DisconnectReason, Latency};
+use super::framing::drive_connection;
+use super::reconnect::Outbox;
+use super::queue::QueueMetrics;
+use super::TcpConfig;
 
-use super::{PuckNetworkResult, bind_transport, PoisonPill};
+use super::{PuckNetworkResult, PuckNetworkError, EventRelay, bind_transport, until_shutdown, await_start, PoisonPill};
 
 
 #[derive(Clone)]
@@ -31,102 +40,222 @@ pub struct ClientEventHandler<CIE, COE> { // this is a "logical" handle for the
 #[derive(Debug, Clone)]
 pub struct ChannelToServer<COE> { // <SE, CE>
     pub sender: UnboundedSender<COE>,
+    pub queue: QueueMetrics, // the current connection's send queue
 }
 
 #[derive(Debug, Clone)]
 pub enum ClientInboundEvent<CIE, COE> {
-    // failed to connect in the first place?
-    FailedToConnect { address: SocketAddr },
-    ServerConnected { address: SocketAddr, channel_to_server: ChannelToServer<COE> }, // that is NOT good enough ..
+    FailedToConnect { address: SocketAddr }, // couldn't connect, or reconnect, within the reconnect policy's attempts
+    ServerConnected { address: SocketAddr, channel_to_server: ChannelToServer<COE> }, // the channel stays good across reconnects, messages are buffered while disconnected
+    Reconnecting { address: SocketAddr, attempt: u32 }, // from 1, after a failed connect or a lost connection
+    Reconnected { address: SocketAddr }, // the ServerConnected channel_to_server is live again
     ServerMessage { address: SocketAddr, event: CIE },
-    ServerDisconnected { address: SocketAddr },
+    ServerDisconnected { address: SocketAddr, reason: DisconnectReason },
+    ServerLatency { address: SocketAddr, latency: Latency }, // after every answered heartbeat
+    HandshakeRejected { address: SocketAddr, local: Handshake, remote: Option<Handshake> }, // remote is what the server expected, None if it didn't answer with a handshake
     ClientFinished { address:SocketAddr }, // unsure of if we should have this one
 }
 
-pub fn run_client<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address:SocketAddr) -> PuckNetworkResult<PoisonPill>
+pub fn run_client<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address:SocketAddr, schema_hash: u64, config: TcpConfig) -> PuckNetworkResult<PoisonPill>
     where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
     let (poison_sender, poison_receiver) = oneshot::channel();
 
+    let (started_sender, started_receiver) = std::sync::mpsc::channel();
+
     let join_handle = thread::spawn(move || {
         println!("TCPClient :: starting");
-        // create_server(server_handle, bind_address, poison_receiver);
-
-        connect_client_to::<CIE, COE, C>(client_handler, server_address, poison_receiver);
+        connect_client_to::<CIE, COE, C>(client_handler, server_address, schema_hash, config, started_sender, poison_receiver);
         println!("TCPClient :: finished");
         12
     });
 
+    // connecting happens in the background, this only fails if the thread couldn't get going
+    await_start(started_receiver)?;
+
     Ok(PoisonPill {
         sender: poison_sender,
         join_handle: join_handle,
     })
 }
 
-fn connect_client_to<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address:SocketAddr, poison_receiver: oneshot::Receiver<u32>)
+// everything a connection attempt needs, shared by every attempt for the life of the client
+struct ClientSession<CIE, COE> {
+    events: EventRelay<ClientInboundEvent<CIE, COE>>,
+    server_address: SocketAddr,
+    handshake: Handshake,
+    config: TcpConfig,
+    handle: Handle,
+    outbox: Rc<RefCell<Outbox<COE>>>,
+    channel_to_server: RefCell<Option<ChannelToServer<COE>>>, // handed out on the first connection, it carries over reconnects
+    queue: QueueMetrics, // shared by every connection in turn
+}
+
+enum HandshakeOutcome<K, S> {
+    Accepted(K, S),
+    Rejected(Option<Handshake>), // what the server expected, None if it didn't answer with a handshake
+}
+
+fn connect_client_to<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address:SocketAddr, schema_hash: u64, config: TcpConfig, started: std::sync::mpsc::Sender<PuckNetworkResult<()>>, poison_receiver: oneshot::Receiver<u32>)
     where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
-    let mut core = Core::new().expect("TCPCLIENT A NEW CORE");
+    let (events, gone_receiver) = EventRelay::new(client_handler.sender);
+
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
+    let _ = started.send(Ok(()));
     let handle = core.handle();
-    let tcp = TcpStream::connect(&server_address, &handle);
 
-    let client_handler_copy = client_handler.clone();
+    let finished_events = events.clone();
 
-    let client = tcp.and_then(move |stream| {
-        let (sink, stream) = bind_transport(stream).split();
+    // the app's channel outlives any one connection, messages wait in the outbox while we're between them
+    let (to_server_tx, to_server_rx) = futures::sync::mpsc::unbounded::<COE>();
+    let outbox = Rc::new(RefCell::new(Outbox::new(config.reconnect.buffer)));
+    let forward_outbox = outbox.clone();
+    let close_outbox = outbox.clone();
+    handle.spawn(to_server_rx.for_each(move |msg| {
+        forward_outbox.borrow_mut().push(msg);
+        Ok(())
+    }).then(move |_| {
+        close_outbox.borrow_mut().close();
+        Ok(())
+    }));
+
+    let queue = QueueMetrics::new();
+    let session = Rc::new(ClientSession {
+        events,
+        server_address,
+        handshake: Handshake::new::<CIE, COE, C>(schema_hash),
+        config,
+        handle,
+        outbox,
+        channel_to_server: RefCell::new(Some(ChannelToServer { sender: to_server_tx, queue: queue.clone() })),
+        queue,
+    });
 
-        let client_copy = client_handler.clone();
+    attempt_connection::<CIE, COE, C>(session, 0);
 
-        let (to_server_tx, to_server_rx) = futures::sync::mpsc::unbounded::<COE>();
-        let channel_to_server = ChannelToServer { sender: to_server_tx };
-        client_copy.sender.send(ClientInboundEvent::ServerConnected { address: server_address, channel_to_server: channel_to_server }).expect("TCPCLIENT SENDS SERVERCONNECTED");
+    if let Ok(shutdown) = core.run(until_shutdown(poison_receiver, gone_receiver)) {
+        println!("TCPClient :: shutting down -> {:?}", shutdown);
+    }
 
-        let socket_reader = stream.for_each(move |m| {
-            println!("TCPClient :: hey mang, I got a message -> {:?}", m);
+    finished_events.send(ClientInboundEvent::ClientFinished { address: server_address });
+}
 
-            match C::deserialize_incoming(&m) {
-                Ok(ie) => {
-                    println!("TCPClient :: received event {:?}", ie);
-                    client_handler.sender.send(ClientInboundEvent::ServerMessage { address: server_address, event : ie }).expect("TCPCLIENT SENDS SERVERMESSAGE");
-                },
-                Err(e) => {
-                    println!("TCPClient :: couldnt deser incoming event -> {:?}", e);
+// attempt 0 is the first connection, retries count up from 1 and reset once we're connected
+fn attempt_connection<CIE, COE, C>(session: Rc<ClientSession<CIE, COE>>, attempt: u32)
+    where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
+    let tcp = TcpStream::connect(&session.server_address, &session.handle);
+    let handshake = session.handshake;
+    let max_frame_length = session.config.limits.max_frame_length;
+
+    // we send our handshake and wait for the server's verdict before anything else, connecting included this gets one heartbeat timeout
+    let handshaken = handshake_within(tcp.and_then(move |stream| {
+        let (sink, stream) = bind_transport(stream, max_frame_length).split();
+        future::result(handshake_frame(&handshake)).and_then(move |handshake_bytes| sink.send(handshake_bytes)).and_then(move |sink| {
+            stream.into_future().map_err(|(e, _)| e).map(move |(first, stream)| {
+                match first.and_then(|bytes| deserialize_bincode::<HandshakeReply>(&bytes).ok()) {
+                    Some(HandshakeReply::Accepted) => HandshakeOutcome::Accepted(sink, stream),
+                    Some(HandshakeReply::Rejected { expected }) => HandshakeOutcome::Rejected(Some(expected)),
+                    None => HandshakeOutcome::Rejected(None),
                 }
-            }
-
-            Ok(())
-        });
-
-        let socket_writer = to_server_rx.fold(sink, |sink, msg| {
-            println!("TCPClient :: writing an outbound event to the server -> {:?}", msg);
-
-            let mut some_bytes : BytesMut = BytesMut::new();
-            match C::serialize_outgoing(&msg, &mut some_bytes) {
-                Ok(()) => (),
-                Err(e) => println!("TCPClient :: couldnt serialize event -> {:?}", e),
-            }
-
-            let amt = sink.send(some_bytes);
-
-            amt.map_err(|_| ())
-        });
-
-
-        let socket_reader = socket_reader.map_err(|_| ());
-        let connection = socket_reader.map(|_| ()).select(socket_writer.map(|_| ()));
-        handle.spawn(connection.then(move |_| {
-            // connections.borrow_mut().remove(&addr);
-            println!("TcpClient :: Connection {} close to server.", server_address);
-            client_copy.sender.send(ClientInboundEvent::ServerDisconnected { address: server_address } ).expect("TCPCLIENT SENDS SERVERDISCONNECT");
-            Ok(())
-        }));
-
+            })
+        })
+    }), session.config.heartbeat.timeout, &session.handle);
+
+    let handle = session.handle.clone();
+    handle.spawn(handshaken.then(move |outcome| {
+        let server_address = session.server_address;
+        match outcome {
+            Ok(HandshakeOutcome::Accepted(sink, stream)) => connected::<CIE, COE, C, _, _>(session, sink, stream),
+            Ok(HandshakeOutcome::Rejected(remote)) => {
+                // trying again won't change the verdict
+                println!("TCPClient :: handshake rejected by {}, they expected {:?}", server_address, remote);
+                session.events.send(ClientInboundEvent::HandshakeRejected { address: server_address, local: session.handshake, remote });
+            },
+            Err(e) => {
+                println!("TCPClient :: couldnt connect to {} -> {:?}", server_address, e);
+                if e.kind() == io::ErrorKind::TimedOut {
+                    session.events.send(ClientInboundEvent::ServerDisconnected { address: server_address, reason: DisconnectReason::TimedOut });
+                }
+                reconnect::<CIE, COE, C>(session, attempt + 1);
+            },
+        }
         Ok(())
-    });
-
-    let without_error = client.map_err(|_| () );
+    }));
+}
 
-    core.handle().spawn(without_error);
+fn connected<CIE, COE, C, K, S>(session: Rc<ClientSession<CIE, COE>>, sink: K, stream: S)
+    where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE>,
+          S : Stream<Item=BytesMut, Error=io::Error> + 'static, K : Sink<SinkItem=BytesMut, SinkError=io::Error> + 'static {
+    let server_address = session.server_address;
+
+    let (connection_tx, connection_rx) = futures::sync::mpsc::unbounded::<COE>();
+    session.outbox.borrow_mut().connect(connection_tx);
+
+    let first_connection = session.channel_to_server.borrow_mut().take();
+    let connected = match first_connection {
+        Some(channel_to_server) => ClientInboundEvent::ServerConnected { address: server_address, channel_to_server },
+        None => ClientInboundEvent::Reconnected { address: server_address },
+    };
+    session.events.send(connected);
+
+    let message_events = session.events.clone();
+    let latency_events = session.events.clone();
+    let connection = drive_connection::<_, _, CIE, COE, C, _, _>((sink, stream), connection_rx, session.config, session.queue.clone(), &session.handle, move |ie| {
+        message_events.send(ClientInboundEvent::ServerMessage { address: server_address, event : ie });
+    }, move |latency| {
+        latency_events.send(ClientInboundEvent::ServerLatency { address: server_address, latency });
+    });
 
-    core.run(poison_receiver).expect("TCPCLIENT RUN");
+    let handle = session.handle.clone();
+    handle.spawn(connection.then(move |reason| {
+        let reason = reason.unwrap_or(DisconnectReason::Dropped);
+        println!("TcpClient :: Connection {} close to server -> {:?}", server_address, reason);
+        session.outbox.borrow_mut().disconnect();
+        session.events.send(ClientInboundEvent::ServerDisconnected { address: server_address, reason: reason.clone() });
+        // dropped means we let go of it ourselves
+        if reason != DisconnectReason::Dropped {
+            reconnect::<CIE, COE, C>(session, 1);
+        }
+        Ok(())
+    }));
+}
 
-    client_handler_copy.sender.send(ClientInboundEvent::ClientFinished { address: server_address }).expect("TCPCLIENT CLIENTFINISHED SEND");
+// waits out the policy's backoff then tries again, or gives up with FailedToConnect
+fn reconnect<CIE, COE, C>(session: Rc<ClientSession<CIE, COE>>, attempt: u32)
+    where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
+    let server_address = session.server_address;
+    if session.outbox.borrow().closed {
+        return; // nobody is left to send anything
+    }
+
+    let backoff = match session.config.reconnect.backoff(attempt) {
+        Some(backoff) => backoff,
+        None => {
+            println!("TCPClient :: giving up on {} after {} attempts", server_address, attempt - 1);
+            session.events.send(ClientInboundEvent::FailedToConnect { address: server_address });
+            return;
+        },
+    };
+
+    println!("TCPClient :: reconnecting to {} in {:?} (attempt {})", server_address, backoff, attempt);
+    session.events.send(ClientInboundEvent::Reconnecting { address: server_address, attempt });
+
+    match Timeout::new(backoff, &session.handle) {
+        Ok(timeout) => {
+            let handle = session.handle.clone();
+            handle.spawn(timeout.then(move |_| {
+                attempt_connection::<CIE, COE, C>(session, attempt);
+                Ok(())
+            }));
+        },
+        Err(e) => {
+            println!("TCPClient :: couldnt wait out the backoff, reconnecting now -> {:?}", e);
+            attempt_connection::<CIE, COE, C>(session, attempt);
+        },
+    }
 }
@@ -144,12 +144,23 @@ impl<E> DeserializeCodec<E> for BincodeCodec where E : DeserializeOwned {
 }
 
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CodecKind {
+    Json,
+    Bincode,
+}
+
 pub trait AsymmetricCodec<IE, OE> where OE : Serialize, IE : DeserializeOwned { // for client <-> server use
+    fn kind() -> CodecKind; // both ends must agree, checked in the handshake
     fn serialize_outgoing(oe: &OE, bytes: &mut BytesMut) -> Result<(), CodecError>;
     fn deserialize_incoming(bytes: &[u8]) -> Result<IE, CodecError>;
 }
 
 impl<IE, OE> AsymmetricCodec<IE, OE> for JsonCodec where OE : Serialize, IE : DeserializeOwned {
+    fn kind() -> CodecKind {
+        CodecKind::Json
+    }
+
     fn serialize_outgoing(oe: &OE, bytes: &mut BytesMut) -> Result<(), CodecError> {
         serialize_json_bytes(oe, bytes)
     }
@@ -161,6 +172,10 @@ impl<IE, OE> AsymmetricCodec<IE, OE> for JsonCodec where OE : Serialize, IE : De
 
 
 impl<IE, OE> AsymmetricCodec<IE, OE> for BincodeCodec where OE : Serialize, IE : DeserializeOwned {
+    fn kind() -> CodecKind {
+        CodecKind::Bincode
+    }
+
     fn serialize_outgoing(oe: &OE, bytes: &mut BytesMut) -> Result<(), CodecError> {
         serialize_bincode_bytes(oe, bytes)
     }
@@ -0,0 +1,485 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::collections::BinaryHeap;
+use std::cmp::{self, Ordering};
+use std::fmt::Debug;
+use std::thread;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use rand;
+
+use futures;
+use futures::Async;
+use futures::executor::{self, Spawn, Notify};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+
+use bytes::BytesMut;
+
+use TreeMap;
+use super::codec::AsymmetricCodec;
+use super::server::{ServerEventHandler, ServerInboundEvent};
+use super::client::{ClientEventHandler, ClientInboundEvent, ChannelToServer};
+use super::heartbeat::Latency;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NetworkConditions {
+    pub latency: Duration, // one way, added to every message and event
+    pub jitter: Duration, // up to this much more, at random, per message
+    pub loss: f64, // 0 to 1, chance a datagram is lost, only udp transports with UdpConfig::conditions set lose anything
+    pub bandwidth: Option<u64>, // bytes per second each way per connection, messages queue behind each other past it
+    pub reorder: f64, // 0 to 1, chance a message is held back (by up to latency + jitter) and overtaken
+}
+
+impl NetworkConditions {
+    pub fn perfect() -> NetworkConditions {
+        NetworkConditions {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            bandwidth: None,
+            reorder: 0.0,
+        }
+    }
+}
+
+impl Default for NetworkConditions {
+    fn default() -> NetworkConditions {
+        NetworkConditions::perfect()
+    }
+}
+
+// shared with the conditioner (and udp transports), changes apply to messages from then on
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    current: Arc<Mutex<NetworkConditions>>,
+}
+
+impl Conditions {
+    pub fn new(conditions: NetworkConditions) -> Conditions {
+        Conditions {
+            current: Arc::new(Mutex::new(conditions)),
+        }
+    }
+
+    pub fn get(&self) -> NetworkConditions {
+        match self.current.lock() {
+            Ok(current) => *current,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    pub fn set(&self, conditions: NetworkConditions) {
+        self.update(|current| *current = conditions);
+    }
+
+    pub fn update<F>(&self, f: F) where F : FnOnce(&mut NetworkConditions) {
+        match self.current.lock() {
+            Ok(mut current) => f(&mut current),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+
+    // rolls for one datagram
+    pub fn lose(&self) -> bool {
+        let loss = self.get().loss;
+        loss > 0.0 && rand::random::<f64>() < loss
+    }
+}
+
+// wraps a server transport, hand the returned handler to run_server, run_udp_server, a Loopback ...
+// the conditioner stops once the transport has dropped every copy of it and everything held has been delivered
+pub fn condition_server<SIE, SOE, C>(server_handler: ServerEventHandler<SIE, SOE>, conditions: Conditions) -> ServerEventHandler<SIE, SOE>
+    where SIE : Serialize + DeserializeOwned + Send + Debug + 'static, SOE : Serialize + DeserializeOwned + Send + Debug + 'static, C : AsymmetricCodec<SIE, SOE> + AsymmetricCodec<SOE, SIE> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        run_conditioner(receiver, server_handler.sender, conditions, |event, outbound| {
+            match event {
+                ServerInboundEvent::ClientConnected { address, client_sender, queue } => {
+                    let client_sender = outbound.intercept(address, client_sender, encoded_len::<SIE, SOE, C>);
+                    (address, Traffic::Connection, ServerInboundEvent::ClientConnected { address, client_sender, queue })
+                },
+                ServerInboundEvent::ClientMessage { address, event } => {
+                    let bytes = encoded_len::<SOE, SIE, C>(&event);
+                    (address, Traffic::Message(bytes), ServerInboundEvent::ClientMessage { address, event })
+                },
+                ServerInboundEvent::ClientLatency { address, latency } => (address, Traffic::Connection, ServerInboundEvent::ClientLatency { address, latency: conditioned_latency(latency, &outbound.conditions.get()) }),
+                ServerInboundEvent::ClientDisconnected { address, reason } => (address, Traffic::Disconnect, ServerInboundEvent::ClientDisconnected { address, reason }),
+                ServerInboundEvent::ClientRejected { address, handshake } => (address, Traffic::Connection, ServerInboundEvent::ClientRejected { address, handshake }),
+                ServerInboundEvent::FailureToBind { address, error } => (address, Traffic::Connection, ServerInboundEvent::FailureToBind { address, error }),
+                ServerInboundEvent::ServerFinished { address } => (address, Traffic::Connection, ServerInboundEvent::ServerFinished { address }),
+            }
+        });
+    });
+    ServerEventHandler { sender }
+}
+
+// as condition_server, for run_client, run_udp_client ...
+pub fn condition_client<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, conditions: Conditions) -> ClientEventHandler<CIE, COE>
+    where CIE : Serialize + DeserializeOwned + Send + Debug + 'static, COE : Serialize + DeserializeOwned + Send + Debug + 'static, C : AsymmetricCodec<CIE, COE> + AsymmetricCodec<COE, CIE> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        run_conditioner(receiver, client_handler.sender, conditions, |event, outbound| {
+            match event {
+                ClientInboundEvent::ServerConnected { address, channel_to_server } => {
+                    let sender = outbound.intercept(address, channel_to_server.sender, encoded_len::<CIE, COE, C>);
+                    (address, Traffic::Connection, ClientInboundEvent::ServerConnected { address, channel_to_server: ChannelToServer { sender, queue: channel_to_server.queue } })
+                },
+                ClientInboundEvent::ServerMessage { address, event } => {
+                    let bytes = encoded_len::<COE, CIE, C>(&event);
+                    (address, Traffic::Message(bytes), ClientInboundEvent::ServerMessage { address, event })
+                },
+                ClientInboundEvent::ServerLatency { address, latency } => (address, Traffic::Connection, ClientInboundEvent::ServerLatency { address, latency: conditioned_latency(latency, &outbound.conditions.get()) }),
+                ClientInboundEvent::FailedToConnect { address } => (address, Traffic::Connection, ClientInboundEvent::FailedToConnect { address }),
+                ClientInboundEvent::Reconnecting { address, attempt } => (address, Traffic::Connection, ClientInboundEvent::Reconnecting { address, attempt }),
+                ClientInboundEvent::Reconnected { address } => (address, Traffic::Connection, ClientInboundEvent::Reconnected { address }),
+                ClientInboundEvent::ServerDisconnected { address, reason } => (address, Traffic::Disconnect, ClientInboundEvent::ServerDisconnected { address, reason }),
+                ClientInboundEvent::HandshakeRejected { address, local, remote } => (address, Traffic::Connection, ClientInboundEvent::HandshakeRejected { address, local, remote }),
+                ClientInboundEvent::ClientFinished { address } => (address, Traffic::Connection, ClientInboundEvent::ClientFinished { address }),
+            }
+        });
+    });
+    ClientEventHandler { sender }
+}
+
+// heartbeats measure the real connection, the game should see the conditioned one
+fn conditioned_latency(latency: Latency, conditions: &NetworkConditions) -> Latency {
+    let round_trip = conditions.latency * 2 + conditions.jitter;
+    Latency {
+        rtt: latency.rtt + round_trip,
+        jitter: latency.jitter + conditions.jitter,
+        last_sample: latency.last_sample + round_trip,
+    }
+}
+
+// the size a message would have on the wire, through the sending end's codec
+fn encoded_len<IE, OE, C>(oe: &OE) -> usize where IE : DeserializeOwned, OE : Serialize, C : AsymmetricCodec<IE, OE> {
+    let mut bytes = BytesMut::new();
+    match C::serialize_outgoing(oe, &mut bytes) {
+        Ok(()) => bytes.len(),
+        Err(_) => 0,
+    }
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let nanos = (duration.as_secs() as f64 * 1_000_000_000.0 + duration.subsec_nanos() as f64) * factor.max(0.0);
+    Duration::new((nanos / 1_000_000_000.0) as u64, (nanos % 1_000_000_000.0) as u32)
+}
+
+// one direction of one connection
+struct Link {
+    busy_until: Instant, // the bandwidth cap is in use until then
+    last_due: Instant, // messages that aren't reordered don't arrive before this
+}
+
+impl Link {
+    fn new(now: Instant) -> Link {
+        Link {
+            busy_until: now,
+            last_due: now,
+        }
+    }
+
+    // when something sent now arrives, bytes is None for connection events, which only get latency and keep their order
+    fn schedule(&mut self, now: Instant, conditions: &NetworkConditions, bytes: Option<usize>) -> Instant {
+        let mut sent = now;
+        let mut due = match bytes {
+            Some(bytes) => {
+                if let Some(bandwidth) = conditions.bandwidth {
+                    let transmit = scale(Duration::from_secs(1), bytes as f64 / cmp::max(bandwidth, 1) as f64);
+                    self.busy_until = cmp::max(self.busy_until, now) + transmit;
+                    sent = self.busy_until;
+                }
+                sent + conditions.latency + scale(conditions.jitter, rand::random::<f64>())
+            },
+            None => sent + conditions.latency,
+        };
+        if bytes.is_some() && conditions.reorder > 0.0 && rand::random::<f64>() < conditions.reorder {
+            due += scale(conditions.latency + conditions.jitter, rand::random::<f64>());
+        } else {
+            due = cmp::max(due, self.last_due);
+            self.last_due = due;
+        }
+        due
+    }
+
+    // nothing in flight, a fresh link would behave the same
+    fn idle(&self, now: Instant) -> bool {
+        self.busy_until <= now && self.last_due <= now
+    }
+}
+
+// the inbound link of every address the transport has told us about
+struct InboundLinks {
+    links: TreeMap<SocketAddr, Link>,
+}
+
+impl InboundLinks {
+    fn schedule(&mut self, now: Instant, conditions: &NetworkConditions, address: SocketAddr, traffic: &Traffic) -> Instant {
+        let bytes = match *traffic {
+            Traffic::Message(bytes) => Some(bytes),
+            Traffic::Connection | Traffic::Disconnect => None,
+        };
+        self.links.entry(address).or_insert_with(|| Link::new(now)).schedule(now, conditions, bytes)
+    }
+
+    // once a disconnect is delivered, unless a new connection from the same address is already using the link
+    fn disconnected(&mut self, now: Instant, address: SocketAddr) {
+        if self.links.get(&address).map(|link| link.idle(now)).unwrap_or(false) {
+            self.links.remove(&address);
+        }
+    }
+}
+
+// how an intercepted inbound event is held
+enum Traffic {
+    Message(usize), // encoded bytes, subject to bandwidth, jitter and reordering
+    Connection, // connection events only get latency and keep their order
+    Disconnect, // as Connection, and the last event on the address's link
+}
+
+enum Held<E, OE> {
+    Inbound(E, Option<SocketAddr>), // with the address when it's a disconnect
+    Outbound(SocketAddr, OE),
+    Close(SocketAddr), // due after everything the game sent before dropping its sender, reordered messages included
+}
+
+// what the conditioner sleeps on, when nothing comes it wakes for the next held message
+enum Wake<E> {
+    Inbound(E),
+    TransportGone,
+    Outbound, // one of the game's senders has something for us
+}
+
+// registered with every outbound receiver we poll, so the game sending something wakes the conditioner
+struct OutboundNotify<E> {
+    wake: Mutex<Sender<Wake<E>>>,
+}
+
+impl<E> Notify for OutboundNotify<E> where E : Send {
+    fn notify(&self, _id: usize) {
+        if let Ok(wake) = self.wake.lock() {
+            let _ = wake.send(Wake::Outbound);
+        }
+    }
+}
+
+struct Delayed<E, OE> {
+    due: Instant,
+    seq: u64, // ties go in the order they were held
+    held: Held<E, OE>,
+}
+
+impl<E, OE> PartialEq for Delayed<E, OE> {
+    fn eq(&self, other: &Delayed<E, OE>) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl<E, OE> Eq for Delayed<E, OE> {}
+
+impl<E, OE> PartialOrd for Delayed<E, OE> {
+    fn partial_cmp(&self, other: &Delayed<E, OE>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E, OE> Ord for Delayed<E, OE> {
+    fn cmp(&self, other: &Delayed<E, OE>) -> Ordering {
+        // reversed, BinaryHeap is a max heap and we want the earliest
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+struct OutboundConnection<OE> {
+    receiver: Option<Spawn<UnboundedReceiver<OE>>>, // None once the game has dropped every sender
+    sender: UnboundedSender<OE>, // the transport's
+    link: Link,
+    last_held: Instant, // the latest due of anything held for this connection
+    encoded_len: fn(&OE) -> usize,
+}
+
+pub struct Outbound<OE> {
+    pub conditions: Conditions,
+    connections: TreeMap<SocketAddr, OutboundConnection<OE>>,
+}
+
+impl<OE> Outbound<OE> {
+    // the game gets a sender of ours, what it sends is held before going on to the transport's
+    fn intercept(&mut self, address: SocketAddr, sender: UnboundedSender<OE>, encoded_len: fn(&OE) -> usize) -> UnboundedSender<OE> {
+        let (tx, rx) = futures::sync::mpsc::unbounded();
+        let now = Instant::now();
+        self.connections.insert(address, OutboundConnection {
+            receiver: Some(executor::spawn(rx)),
+            sender,
+            link: Link::new(now),
+            last_held: now,
+            encoded_len,
+        });
+        tx
+    }
+}
+
+fn run_conditioner<E, OE, F>(inbound: Receiver<E>, deliver: Sender<E>, conditions: Conditions, mut intercept: F)
+    where E : Send + 'static, F : FnMut(E, &mut Outbound<OE>) -> (SocketAddr, Traffic, E) {
+    let (wake_sender, wakes) = channel();
+    let forward = wake_sender.clone();
+    thread::spawn(move || {
+        for event in inbound.iter() {
+            if forward.send(Wake::Inbound(event)).is_err() {
+                return;
+            }
+        }
+        let _ = forward.send(Wake::TransportGone);
+    });
+
+    let notify = Arc::new(OutboundNotify { wake: Mutex::new(wake_sender) });
+    let mut outbound = Outbound { conditions, connections: TreeMap::new() };
+    let mut inbound_links = InboundLinks { links: TreeMap::new() };
+    let mut held : BinaryHeap<Delayed<E, OE>> = BinaryHeap::new();
+    let mut next_seq = 0;
+    let mut transport_gone = false;
+
+    loop {
+        // sleeps until the earliest held message is due, or indefinitely with nothing held
+        let now = Instant::now();
+        let woken = match held.peek().map(|d| d.due) {
+            Some(due) if due <= now => wakes.try_recv().ok(),
+            Some(due) => match wakes.recv_timeout(due - now) {
+                Ok(wake) => Some(wake),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+            },
+            None => wakes.recv().ok(),
+        };
+
+        let now = Instant::now();
+        let network = outbound.conditions.get();
+        match woken {
+            Some(Wake::Inbound(event)) => {
+                let (address, traffic, event) = intercept(event, &mut outbound);
+                let due = inbound_links.schedule(now, &network, address, &traffic);
+                let closes = match traffic {
+                    Traffic::Disconnect => Some(address),
+                    Traffic::Message(_) | Traffic::Connection => None,
+                };
+                held.push(Delayed { due, seq: next_seq, held: Held::Inbound(event, closes) });
+                next_seq += 1;
+            },
+            Some(Wake::TransportGone) => transport_gone = true,
+            Some(Wake::Outbound) | None => (),
+        }
+
+        for (address, connection) in outbound.connections.iter_mut() {
+            let mut closed = false;
+            if let Some(ref mut receiver) = connection.receiver {
+                loop {
+                    match receiver.poll_stream_notify(&notify, 0) {
+                        Ok(Async::Ready(Some(oe))) => {
+                            let bytes = (connection.encoded_len)(&oe);
+                            let due = connection.link.schedule(now, &network, Some(bytes));
+                            connection.last_held = cmp::max(connection.last_held, due);
+                            held.push(Delayed { due, seq: next_seq, held: Held::Outbound(*address, oe) });
+                            next_seq += 1;
+                        },
+                        Ok(Async::Ready(None)) | Err(()) => {
+                            closed = true;
+                            break;
+                        },
+                        Ok(Async::NotReady) => break,
+                    }
+                }
+            }
+            if closed {
+                connection.receiver = None;
+                let due = cmp::max(connection.link.schedule(now, &network, None), connection.last_held);
+                held.push(Delayed { due, seq: next_seq, held: Held::Close(*address) });
+                next_seq += 1;
+            }
+        }
+
+        while held.peek().map(|d| d.due <= now).unwrap_or(false) {
+            if let Some(delayed) = held.pop() {
+                match delayed.held {
+                    Held::Inbound(event, closes) => {
+                        if deliver.send(event).is_err() {
+                            return; // the game's gone
+                        }
+                        if let Some(address) = closes {
+                            inbound_links.disconnected(now, address);
+                        }
+                    },
+                    Held::Outbound(address, oe) => {
+                        if let Some(connection) = outbound.connections.get(&address) {
+                            let _ = connection.sender.unbounded_send(oe);
+                        }
+                    },
+                    Held::Close(address) => {
+                        outbound.connections.remove(&address); // dropping the transport's sender ends the connection
+                    },
+                }
+            }
+        }
+
+        if transport_gone && held.is_empty() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use network::codec::BincodeCodec;
+    use network::queue::QueueMetrics;
+
+    #[test]
+    fn held_messages_go_out_before_the_connection_closes() {
+        let conditions = Conditions::new(NetworkConditions { latency: Duration::from_millis(20), jitter: Duration::from_millis(10), reorder: 1.0, ..NetworkConditions::perfect() });
+        let (sender, game) = channel::<ClientInboundEvent<u32, u32>>();
+        let transport = condition_client::<u32, u32, BincodeCodec>(ClientEventHandler { sender }, conditions);
+        let address = "127.0.0.1:1".parse().unwrap();
+
+        let (to_server, from_game) = futures::sync::mpsc::unbounded::<u32>();
+        let sent_at = Instant::now();
+        transport.sender.send(ClientInboundEvent::ServerConnected { address, channel_to_server: ChannelToServer { sender: to_server, queue: QueueMetrics::new() } }).unwrap();
+        let conditioned = match game.recv_timeout(Duration::from_secs(5)) {
+            Ok(ClientInboundEvent::ServerConnected { channel_to_server, .. }) => channel_to_server.sender,
+            _ => panic!("expected the connection"),
+        };
+        assert!(sent_at.elapsed() >= Duration::from_millis(20));
+
+        for n in 0..20 {
+            conditioned.unbounded_send(n).unwrap();
+        }
+        drop(conditioned);
+        let mut arrived : Vec<u32> = from_game.wait().map(|n| n.unwrap()).collect();
+        arrived.sort();
+        assert_eq!(arrived, (0..20).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn inbound_links_are_forgotten_once_idle_after_a_disconnect() {
+        let network = NetworkConditions { latency: Duration::from_millis(20), ..NetworkConditions::perfect() };
+        let mut links = InboundLinks { links: TreeMap::new() };
+        let first = "127.0.0.1:1".parse().unwrap();
+        let second = "127.0.0.1:2".parse().unwrap();
+        let now = Instant::now();
+
+        links.schedule(now, &network, first, &Traffic::Connection);
+        links.schedule(now, &network, first, &Traffic::Message(100));
+        let due = links.schedule(now, &network, first, &Traffic::Disconnect);
+        links.schedule(now, &network, second, &Traffic::Connection);
+        links.disconnected(due, first);
+        assert_eq!(links.links.keys().cloned().collect::<Vec<SocketAddr>>(), vec![second]);
+
+        // the same address connecting again before the disconnect is delivered keeps its link
+        let due = links.schedule(now, &network, second, &Traffic::Disconnect);
+        links.schedule(due, &network, second, &Traffic::Connection);
+        links.disconnected(due, second);
+        assert_eq!(links.links.len(), 1);
+    }
+}
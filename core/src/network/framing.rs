@@ -0,0 +1,246 @@
+use std::io;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Instant;
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures;
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::{future, Future, Stream, Sink, Poll, Async, AsyncSink};
+
+use tokio_core::reactor::{Handle, Interval};
+
+use bytes::{BytesMut, BufMut};
+
+use super::codec::{AsymmetricCodec, CodecError};
+use super::heartbeat::{DisconnectReason, Latency, Liveness};
+use super::queue::{SendQueue, QueueMetrics, Pushed};
+use super::limits::Limiter;
+use super::TcpConfig;
+
+// after the handshake every frame starts with one of these
+pub const MESSAGE_FRAME : u8 = 0;
+pub const PING_FRAME : u8 = 1;
+pub const PONG_FRAME : u8 = 2;
+
+pub enum Frame<'a> {
+    Message(&'a [u8]), // codec encoded
+    Ping(u64),
+    Pong(u64),
+}
+
+pub fn decode_frame<'a>(bytes: &'a [u8]) -> Option<Frame<'a>> {
+    match bytes.split_first() {
+        Some((&MESSAGE_FRAME, rest)) => Some(Frame::Message(rest)),
+        Some((&PING_FRAME, rest)) if rest.len() == 8 => Some(Frame::Ping(read_u64(rest))),
+        Some((&PONG_FRAME, rest)) if rest.len() == 8 => Some(Frame::Pong(read_u64(rest))),
+        _ => None,
+    }
+}
+
+pub fn message_frame<IE, OE, C>(oe: &OE) -> Result<BytesMut, CodecError> where OE : Serialize, IE : DeserializeOwned, C : AsymmetricCodec<IE, OE> {
+    let mut bytes = BytesMut::with_capacity(64);
+    bytes.put_u8(MESSAGE_FRAME);
+    C::serialize_outgoing(oe, &mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn control_frame(tag: u8, nonce: u64) -> BytesMut {
+    let mut bytes = BytesMut::with_capacity(9);
+    bytes.put_u8(tag);
+    for shift in (0..8).rev() {
+        bytes.put_u8((nonce >> (shift * 8)) as u8);
+    }
+    bytes
+}
+
+fn read_u64(bytes: &[u8]) -> u64 { // big endian
+    bytes.iter().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
+// reads, writes and heartbeats one handshaken connection until it ends, resolving to why it ended
+// messages are written until every sender for outgoing is dropped, metrics follows the connection's send queue
+pub fn drive_connection<S, K, IE, OE, C, F, L>((sink, stream): (K, S), outgoing: UnboundedReceiver<OE>, config: TcpConfig, metrics: QueueMetrics, handle: &Handle, mut on_message: F, mut on_latency: L) -> Box<dyn Future<Item=DisconnectReason, Error=()>>
+    where S : Stream<Item=BytesMut, Error=io::Error> + 'static, K : Sink<SinkItem=BytesMut, SinkError=io::Error> + 'static,
+          IE : DeserializeOwned + 'static, OE : Serialize + Debug + 'static, C : AsymmetricCodec<IE, OE>,
+          F : FnMut(IE) + 'static, L : FnMut(Latency) + 'static {
+    let liveness = Rc::new(RefCell::new(Liveness::new(Instant::now())));
+    let (control_send, control_receive) = futures::sync::mpsc::unbounded::<BytesMut>();
+
+    let reader_liveness = liveness.clone();
+    let pong_send = control_send.clone();
+    let mut limiter = Limiter::new(config.limits, Instant::now());
+    let socket_reader = stream.map_err(|e| DisconnectReason::Error(e.kind())).for_each(move |m| {
+        let now = Instant::now();
+        reader_liveness.borrow_mut().received(now);
+        let strike = if !limiter.admit(now) {
+            Some(Strike::RateLimited)
+        } else {
+            match decode_frame(&m) {
+                Some(Frame::Message(bytes)) => {
+                    match C::deserialize_incoming(bytes) {
+                        Ok(ie) => {
+                            on_message(ie);
+                            None
+                        },
+                        Err(e) => Some(Strike::Undeserializable(e)),
+                    }
+                },
+                Some(Frame::Ping(nonce)) => {
+                    let _ = pong_send.unbounded_send(control_frame(PONG_FRAME, nonce));
+                    None
+                },
+                Some(Frame::Pong(nonce)) => {
+                    let latency = reader_liveness.borrow_mut().pong(nonce, now);
+                    if let Some(latency) = latency {
+                        on_latency(latency);
+                    }
+                    None
+                },
+                None => Some(Strike::UnknownFrame { length: m.len() }),
+            }
+        };
+        if let Some(strike) = strike {
+            // a peer sending junk as fast as it can shouldn't get a log line per frame
+            if limiter.strikes == 0 {
+                println!("TCP :: dropping a frame -> {}, further strikes aren't logged", strike.describe());
+            }
+            if limiter.strike() {
+                println!("TCP :: disconnecting after {} strikes, the last -> {}", limiter.strikes, strike.describe());
+                return Err(DisconnectReason::Misbehaved);
+            }
+        }
+        Ok(())
+    }).then(|r| Ok::<DisconnectReason, ()>(match r {
+        Ok(()) => DisconnectReason::Closed,
+        Err(reason) => reason,
+    }));
+
+    let socket_writer = Writer {
+        sink,
+        outgoing,
+        outgoing_done: false,
+        control: control_receive,
+        control_queued: VecDeque::new(),
+        queue: SendQueue::new(config.send_queue, metrics),
+        serialize: message_frame::<IE, OE, C>,
+    }.then(|r| Ok::<DisconnectReason, ()>(match r {
+        Ok(reason) => reason,
+        Err(e) => DisconnectReason::Error(e.kind()),
+    }));
+
+    let heartbeat_config = config.heartbeat;
+    let heartbeat = match Interval::new(heartbeat_config.interval, handle) {
+        Ok(interval) => {
+            let heartbeat = interval.for_each(move |_| {
+                let now = Instant::now();
+                let mut liveness = liveness.borrow_mut();
+                if liveness.timed_out(now, &heartbeat_config) {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "heartbeat timed out"));
+                }
+                let nonce = liveness.ping(now);
+                let _ = control_send.unbounded_send(control_frame(PING_FRAME, nonce));
+                Ok(())
+            }).then(|r| Ok::<DisconnectReason, ()>(match r {
+                Ok(()) => DisconnectReason::Dropped,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => DisconnectReason::TimedOut,
+                Err(e) => DisconnectReason::Error(e.kind()),
+            }));
+            future::Either::A(heartbeat)
+        },
+        Err(e) => future::Either::B(future::ok(DisconnectReason::Error(e.kind()))),
+    };
+
+    let connection = socket_reader.select(socket_writer).map(|(reason, _)| reason).map_err(|_| ())
+        .select(heartbeat).map(|(reason, _)| reason).map_err(|_| ());
+    Box::new(connection)
+}
+
+// why a frame was dropped, each one counts against ConnectionLimits::max_strikes
+enum Strike {
+    RateLimited,
+    UnknownFrame { length: usize }, // the bytes themselves are never logged
+    Undeserializable(CodecError),
+}
+
+impl Strike {
+    fn describe(&self) -> String {
+        match *self {
+            Strike::RateLimited => "over the message rate limit".to_string(),
+            Strike::UnknownFrame { length } => format!("unknown frame of {} bytes", length),
+            Strike::Undeserializable(ref e) => format!("couldn't deserialize message {:?}", e),
+        }
+    }
+}
+
+// app messages wait in a bounded queue while the socket is busy, control frames skip it but are coalesced by kind
+struct Writer<K, OE> {
+    sink: K,
+    outgoing: UnboundedReceiver<OE>,
+    outgoing_done: bool,
+    control: UnboundedReceiver<BytesMut>,
+    control_queued: VecDeque<BytesMut>,
+    queue: SendQueue<BytesMut>,
+    serialize: fn(&OE) -> Result<BytesMut, CodecError>,
+}
+
+impl<K, OE> Future for Writer<K, OE> where K : Sink<SinkItem=BytesMut, SinkError=io::Error>, OE : Debug {
+    type Item = DisconnectReason; // Dropped once every sender is gone and everything queued is written
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<DisconnectReason, io::Error> {
+        let closed = || io::Error::other("unbounded receivers don't fail");
+
+        // only the latest ping and pong are worth sending, so a peer pinging faster than we can write doesn't grow this
+        while let Async::Ready(Some(frame)) = self.control.poll().map_err(|_| closed())? {
+            match self.control_queued.iter_mut().find(|queued| queued[0] == frame[0]) {
+                Some(queued) => *queued = frame,
+                None => self.control_queued.push_back(frame),
+            }
+        }
+
+        // everything waiting is taken off the channel, so it's the bounded queue that fills when the socket can't keep up
+        while !self.outgoing_done {
+            match self.outgoing.poll().map_err(|_| closed())? {
+                Async::Ready(Some(msg)) => {
+                    match (self.serialize)(&msg) {
+                        Ok(bytes) => {
+                            if self.queue.push(bytes) == Pushed::Overflowed {
+                                return Ok(Async::Ready(DisconnectReason::Overflowed));
+                            }
+                        },
+                        Err(e) => println!("TCP :: couldnt serialize event {:?} -> {:?}", msg, e),
+                    }
+                },
+                Async::Ready(None) => self.outgoing_done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        while let Some(frame) = self.control_queued.pop_front() {
+            if let AsyncSink::NotReady(frame) = self.sink.start_send(frame)? {
+                self.control_queued.push_front(frame);
+                break;
+            }
+        }
+        if self.control_queued.is_empty() {
+            while let Some(frame) = self.queue.pop() {
+                if let AsyncSink::NotReady(frame) = self.sink.start_send(frame)? {
+                    self.queue.push_front(frame);
+                    break;
+                }
+            }
+        }
+
+        let flushed = self.sink.poll_complete()?.is_ready();
+        if self.outgoing_done && self.queue.is_empty() && self.control_queued.is_empty() && flushed {
+            Ok(Async::Ready(DisconnectReason::Dropped))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
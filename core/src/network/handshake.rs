@@ -0,0 +1,57 @@
+use std::io;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use bytes::BytesMut;
+
+use futures::{future, Future};
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::codec::{AsymmetricCodec, CodecKind, serialize_bincode_bytes};
+
+pub const PROTOCOL_VERSION : u32 = 2; // bump when the framing or handshake itself changes
+
+// the first frame on every connection, always bincode regardless of the codec used afterwards
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub codec: CodecKind,
+    pub schema_hash: u64, // app defined, peers with different message types must differ here
+}
+
+impl Handshake {
+    pub fn new<IE, OE, C>(schema_hash: u64) -> Handshake where OE : Serialize, IE : DeserializeOwned, C : AsymmetricCodec<IE, OE> {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            codec: C::kind(),
+            schema_hash,
+        }
+    }
+}
+
+// the server's answer, nothing else is sent until the client has been accepted
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HandshakeReply {
+    Accepted,
+    Rejected { expected: Handshake },
+}
+
+// handshakes and replies as a frame, as an io error so it can fail a connection future
+pub fn handshake_frame<T>(t: &T) -> io::Result<BytesMut> where T : Serialize {
+    let mut bytes = BytesMut::new();
+    serialize_bincode_bytes(t, &mut bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    Ok(bytes)
+}
+
+// fails with an io error of kind TimedOut unless handshake resolves within timeout, so a silent peer can't hold a connection open
+pub fn handshake_within<F>(handshake: F, timeout: Duration, handle: &Handle) -> Box<dyn Future<Item=F::Item, Error=io::Error>> where F : Future<Error=io::Error> + 'static, F::Item : 'static {
+    match Timeout::new(timeout, handle) {
+        Ok(deadline) => {
+            let timed_out = deadline.and_then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")));
+            Box::new(handshake.select(timed_out).map(|(item, _)| item).map_err(|(e, _)| e))
+        },
+        Err(e) => Box::new(future::err(e)),
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use TreeMap;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration, // a ping goes out this often
+    pub timeout: Duration, // the connection is dropped when nothing at all has arrived for this long
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Closed, // the other end closed the connection
+    Dropped, // we stopped, every sender for the connection was dropped or the network loop shut down
+    TimedOut, // nothing arrived within the heartbeat timeout
+    Overflowed, // the send queue filled under OverflowPolicy::Disconnect, the peer wasn't keeping up
+    Misbehaved, // the peer ran out of strikes, see ConnectionLimits
+    Error(io::ErrorKind),
+}
+
+// smoothed as tcp does it (rfc 6298), jitter is the mean deviation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Latency {
+    pub rtt: Duration,
+    pub jitter: Duration,
+    pub last_sample: Duration,
+}
+
+// per connection ping bookkeeping
+pub struct Liveness {
+    pub last_received: Instant,
+    pub latency: Option<Latency>,
+    next_nonce: u64,
+    pings: TreeMap<u64, Instant>, // unanswered pings by nonce
+}
+
+impl Liveness {
+    pub fn new(now: Instant) -> Liveness {
+        Liveness {
+            last_received: now,
+            latency: None,
+            next_nonce: 0,
+            pings: TreeMap::new(),
+        }
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    pub fn timed_out(&self, now: Instant, config: &HeartbeatConfig) -> bool {
+        now.duration_since(self.last_received) >= config.timeout
+    }
+
+    // returns the nonce to send in the ping
+    pub fn ping(&mut self, now: Instant) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pings.insert(nonce, now);
+        // anything this old was answered by something newer or never will be
+        if nonce >= 16 {
+            self.pings = self.pings.split_off(&(nonce - 16));
+        }
+        nonce
+    }
+
+    // the updated latency, None for pongs we never sent a ping for
+    pub fn pong(&mut self, nonce: u64, now: Instant) -> Option<Latency> {
+        let sent_at = self.pings.remove(&nonce)?;
+        let sample = now.duration_since(sent_at);
+        let latency = match self.latency {
+            None => Latency { rtt: sample, jitter: sample / 2, last_sample: sample },
+            Some(previous) => {
+                let deviation = sample.abs_diff(previous.rtt);
+                Latency {
+                    rtt: (previous.rtt * 7 + sample) / 8,
+                    jitter: (previous.jitter * 3 + deviation) / 4,
+                    last_sample: sample,
+                }
+            },
+        };
+        self.latency = Some(latency);
+        self.latency
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub frames: u32, // allowed per window, heartbeats included
+    pub per: Duration,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_frame_length: usize, // bytes, a peer announcing a longer frame is disconnected with Error(InvalidData)
+    pub rate_limit: Option<RateLimit>, // frames over the limit are dropped unread and count as a strike
+    pub max_strikes: Option<u32>, // undecodable frames and rate limited ones before we disconnect, None never disconnects
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits {
+            max_frame_length: 4 * 1024 * 1024,
+            rate_limit: None,
+            max_strikes: Some(16),
+        }
+    }
+}
+
+// per connection, applied to every frame read
+pub struct Limiter {
+    pub limits: ConnectionLimits,
+    pub strikes: u32,
+    window_start: Instant,
+    in_window: u32,
+}
+
+impl Limiter {
+    pub fn new(limits: ConnectionLimits, now: Instant) -> Limiter {
+        Limiter {
+            limits,
+            strikes: 0,
+            window_start: now,
+            in_window: 0,
+        }
+    }
+
+    // false if the frame is over the rate limit
+    pub fn admit(&mut self, now: Instant) -> bool {
+        match self.limits.rate_limit {
+            Some(rate_limit) => {
+                if now.duration_since(self.window_start) >= rate_limit.per {
+                    self.window_start = now;
+                    self.in_window = 0;
+                }
+                self.in_window += 1;
+                self.in_window <= rate_limit.frames
+            },
+            None => true,
+        }
+    }
+
+    // true once the connection has had too many
+    pub fn strike(&mut self) -> bool {
+        self.strikes += 1;
+        self.limits.max_strikes.map(|max| self.strikes >= max).unwrap_or(false)
+    }
+}
@@ -0,0 +1,265 @@
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::fmt::Debug;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures;
+use futures::Async;
+use futures::executor::{self, Spawn, Notify};
+use futures::sync::mpsc::UnboundedReceiver;
+
+use bytes::BytesMut;
+
+use TreeMap;
+use super::codec::AsymmetricCodec;
+use super::server::{ServerEventHandler, ServerInboundEvent};
+use super::client::{ClientEventHandler, ClientInboundEvent, ChannelToServer};
+use super::heartbeat::DisconnectReason;
+use super::queue::QueueMetrics;
+
+// nothing waits on a loopback channel, pump polls them all
+pub struct NoNotify;
+
+impl Notify for NoNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+struct LoopbackConnection<SIE, SOE> {
+    client: ClientEventHandler<SOE, SIE>,
+    to_server: Spawn<UnboundedReceiver<SIE>>, // from the client's ChannelToServer
+    to_client: Spawn<UnboundedReceiver<SOE>>, // from the server's client_sender
+}
+
+// a server and its clients in one thread, connected by channels instead of sockets
+// every message goes through the codec both ways, nothing moves until pump is called
+pub struct Loopback<SIE, SOE, C> {
+    pub server_address: SocketAddr,
+    server: ServerEventHandler<SIE, SOE>,
+    connections: TreeMap<SocketAddr, LoopbackConnection<SIE, SOE>>, // by client address, so pumping is in a fixed order
+    next_port: u16,
+    notify: Arc<NoNotify>,
+    codec: PhantomData<C>,
+}
+
+impl<SIE, SOE, C> Loopback<SIE, SOE, C> where SIE : Serialize + DeserializeOwned + Debug, SOE : Serialize + DeserializeOwned + Debug, C : AsymmetricCodec<SIE, SOE> + AsymmetricCodec<SOE, SIE> {
+    pub fn new(server: ServerEventHandler<SIE, SOE>, server_address: SocketAddr) -> Loopback<SIE, SOE, C> {
+        Loopback {
+            server_address,
+            server,
+            connections: TreeMap::new(),
+            next_port: 1,
+            notify: Arc::new(NoNotify),
+            codec: PhantomData,
+        }
+    }
+
+    // the client's address as the server sees it, both sides hear about the connection straight away
+    pub fn connect(&mut self, client: ClientEventHandler<SOE, SIE>) -> SocketAddr {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.next_port);
+        self.next_port += 1;
+
+        let (to_server_tx, to_server_rx) = futures::sync::mpsc::unbounded::<SIE>();
+        let (to_client_tx, to_client_rx) = futures::sync::mpsc::unbounded::<SOE>();
+
+        let _ = self.server.sender.send(ServerInboundEvent::ClientConnected { address, client_sender: to_client_tx, queue: QueueMetrics::new() });
+        let _ = client.sender.send(ClientInboundEvent::ServerConnected { address: self.server_address, channel_to_server: ChannelToServer { sender: to_server_tx, queue: QueueMetrics::new() } });
+
+        self.connections.insert(address, LoopbackConnection {
+            client,
+            to_server: executor::spawn(to_server_rx),
+            to_client: executor::spawn(to_client_rx),
+        });
+        address
+    }
+
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        self.connections.keys().cloned().collect()
+    }
+
+    // as if the client's end closed the connection
+    pub fn disconnect(&mut self, address: &SocketAddr) -> bool {
+        match self.connections.remove(address) {
+            Some(connection) => {
+                let _ = self.server.sender.send(ServerInboundEvent::ClientDisconnected { address: *address, reason: DisconnectReason::Closed });
+                let _ = connection.client.sender.send(ClientInboundEvent::ServerDisconnected { address: self.server_address, reason: DisconnectReason::Dropped });
+                true
+            },
+            None => false,
+        }
+    }
+
+    // delivers everything sent so far, clients to server then server to clients, returns how many messages moved
+    // anything sent in response arrives on the next pump
+    pub fn pump(&mut self) -> usize {
+        let mut moved = 0;
+        let mut closed : Vec<(SocketAddr, bool)> = Vec::new(); // and whether it was the server that let go
+
+        for (address, connection) in self.connections.iter_mut() {
+            let (messages, done) = drain(&mut connection.to_server, &self.notify);
+            for message in messages {
+                match round_trip::<SIE, SOE, C>(&message) {
+                    Some(event) => {
+                        moved += 1;
+                        let _ = self.server.sender.send(ServerInboundEvent::ClientMessage { address: *address, event });
+                    },
+                    None => println!("Loopback :: couldnt round trip {:?} from {}", message, address),
+                }
+            }
+            if done {
+                closed.push((*address, false));
+            }
+        }
+
+        for (address, connection) in self.connections.iter_mut() {
+            let (messages, done) = drain(&mut connection.to_client, &self.notify);
+            for message in messages {
+                match round_trip::<SOE, SIE, C>(&message) {
+                    Some(event) => {
+                        moved += 1;
+                        let _ = connection.client.sender.send(ClientInboundEvent::ServerMessage { address: self.server_address, event });
+                    },
+                    None => println!("Loopback :: couldnt round trip {:?} to {}", message, address),
+                }
+            }
+            if done && !closed.iter().any(|&(a, _)| a == *address) {
+                closed.push((*address, true));
+            }
+        }
+
+        // the side that dropped its sender sees Dropped, the other end sees it Closed
+        for (address, by_server) in closed {
+            if let Some(connection) = self.connections.remove(&address) {
+                let (server_reason, client_reason) = if by_server { (DisconnectReason::Dropped, DisconnectReason::Closed) } else { (DisconnectReason::Closed, DisconnectReason::Dropped) };
+                let _ = self.server.sender.send(ServerInboundEvent::ClientDisconnected { address, reason: server_reason });
+                let _ = connection.client.sender.send(ClientInboundEvent::ServerDisconnected { address: self.server_address, reason: client_reason });
+            }
+        }
+
+        moved
+    }
+
+    // pumps until nothing more moves or max_rounds is reached, returns the rounds taken
+    pub fn settle(&mut self, max_rounds: usize) -> usize {
+        let mut rounds = 0;
+        while rounds < max_rounds && self.pump() > 0 {
+            rounds += 1;
+        }
+        rounds
+    }
+
+    // everyone is told the network has finished, as when a PoisonPill is shut down
+    pub fn shutdown(mut self) {
+        let addresses = self.connected();
+        for address in addresses {
+            if let Some(connection) = self.connections.remove(&address) {
+                let _ = self.server.sender.send(ServerInboundEvent::ClientDisconnected { address, reason: DisconnectReason::Dropped });
+                let _ = connection.client.sender.send(ClientInboundEvent::ServerDisconnected { address: self.server_address, reason: DisconnectReason::Dropped });
+                let _ = connection.client.sender.send(ClientInboundEvent::ClientFinished { address: self.server_address });
+            }
+        }
+        let _ = self.server.sender.send(ServerInboundEvent::ServerFinished { address: self.server_address });
+    }
+}
+
+// everything waiting, and whether every sender is gone
+fn drain<E>(receiver: &mut Spawn<UnboundedReceiver<E>>, notify: &Arc<NoNotify>) -> (Vec<E>, bool) {
+    let mut messages = Vec::new();
+    loop {
+        match receiver.poll_stream_notify(notify, 0) {
+            Ok(Async::Ready(Some(e))) => messages.push(e),
+            Ok(Async::Ready(None)) | Err(()) => return (messages, true),
+            Ok(Async::NotReady) => return (messages, false),
+        }
+    }
+}
+
+// out through the sending end's codec and in through the receiving end's
+fn round_trip<E, Other, C>(e: &E) -> Option<E> where E : Serialize + DeserializeOwned, Other : Serialize + DeserializeOwned, C : AsymmetricCodec<Other, E> + AsymmetricCodec<E, Other> {
+    let mut bytes = BytesMut::new();
+    <C as AsymmetricCodec<Other, E>>::serialize_outgoing(e, &mut bytes).ok()?;
+    <C as AsymmetricCodec<E, Other>>::deserialize_incoming(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use event::Event;
+    use network::codec::{BincodeCodec, JsonCodec};
+    use app::{AppServer, AppClient, Owner};
+    use app::messages::{AppClientMessage, AppServerMessage};
+    use app::test_app::{TestApp, TestId, TestEvent, counters};
+
+    type TestLoopback<C> = Loopback<AppClientMessage<TestApp>, AppServerMessage<TestApp>, C>;
+
+    fn client<C>(loopback: &mut TestLoopback<C>) -> (SocketAddr, AppClient<TestApp>) where C : AsymmetricCodec<AppClientMessage<TestApp>, AppServerMessage<TestApp>> + AsymmetricCodec<AppServerMessage<TestApp>, AppClientMessage<TestApp>> {
+        let (sender, receiver) = channel();
+        let address = loopback.connect(ClientEventHandler { sender });
+        (address, AppClient::from_events(loopback.server_address, receiver, None))
+    }
+
+    fn clients_share_one_server<C>() where C : AsymmetricCodec<AppClientMessage<TestApp>, AppServerMessage<TestApp>> + AsymmetricCodec<AppServerMessage<TestApp>, AppClientMessage<TestApp>> {
+        let server_address = "127.0.0.1:7000".parse().unwrap();
+        let (sender, receiver) = channel();
+        let mut loopback : TestLoopback<C> = Loopback::new(ServerEventHandler { sender }, server_address);
+        let mut server = AppServer::from_events(counters(3), server_address, receiver, None);
+        let (a, mut first) = client(&mut loopback);
+        let (b, mut second) = client(&mut loopback);
+
+        server.poll();
+        server.assign(TestId::Counter(0), a);
+        server.assign(TestId::Counter(1), b);
+        server.step();
+        loopback.settle(10);
+        first.poll();
+        second.poll();
+        assert!(first.connected && second.connected);
+        assert_eq!(first.owned, vec![TestId::Counter(0)]);
+        assert_eq!(second.owned, vec![TestId::Counter(1)]);
+
+        // the first client's event for the second's counter is refused
+        first.send(vec![Event::EntityEvent(TestId::Counter(0), TestEvent::Add(100)), Event::EntityEvent(TestId::Counter(1), TestEvent::Add(1000))]);
+        second.send(vec![Event::EntityEvent(TestId::Counter(1), TestEvent::Add(200))]);
+        for _ in 0..3 {
+            loopback.settle(10);
+            server.step();
+        }
+        loopback.settle(10);
+        first.poll();
+        second.poll();
+
+        let entities = &server.simulation.entities;
+        assert!(entities[&TestId::Counter(0)].value >= 100);
+        assert!(entities[&TestId::Counter(1)].value >= 200 && entities[&TestId::Counter(1)].value < 1000);
+        assert_eq!(&first.entities, entities);
+        assert_eq!(&second.entities, entities);
+        assert_eq!(first.tick, server.simulation.tick);
+
+        loopback.disconnect(&b);
+        server.step();
+        loopback.settle(10);
+        second.poll();
+        assert!(!second.connected);
+        assert_eq!(loopback.connected(), vec![a]);
+        assert_eq!(server.clients.len(), 1);
+        assert_eq!(server.ownership.owner(&TestId::Counter(1)), Owner::Server);
+
+        loopback.shutdown();
+        server.poll();
+        first.poll();
+        assert!(server.finished && first.finished);
+    }
+
+    #[test]
+    fn clients_share_one_server_over_bincode() {
+        clients_share_one_server::<BincodeCodec>();
+    }
+
+    #[test]
+    fn clients_share_one_server_over_json() {
+        clients_share_one_server::<JsonCodec>();
+    }
+}
@@ -1,20 +1,41 @@
 use std;
 use std::thread;
 use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::sync::mpsc::{Sender, Receiver};
 
 use tokio_io::codec::length_delimited;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use futures::sync::oneshot;
+use futures::{future, Future};
+
+use self::heartbeat::HeartbeatConfig;
+use self::reconnect::ReconnectPolicy;
+use self::queue::SendQueueConfig;
+use self::limits::ConnectionLimits;
 
 pub mod client;
 pub mod codec;
+pub mod conditioner;
+pub mod handshake;
+pub mod heartbeat;
+pub mod framing;
+pub mod reconnect;
+pub mod queue;
+pub mod limits;
+pub mod loopback;
 pub mod server;
+pub mod udp;
 
 
 #[derive(Debug)]
 pub enum PuckNetworkError {
-    IO(io::Error)
+    IO(io::Error),
+    Bind { address: SocketAddr, error: io::Error },
+    ThreadStopped, // the network thread ended before it could report how starting went
 }
 
 impl From<io::Error> for PuckNetworkError {
@@ -25,8 +46,16 @@ impl From<io::Error> for PuckNetworkError {
 
 pub type PuckNetworkResult<T> = Result<T, PuckNetworkError>;
 
-pub fn bind_transport<T: AsyncRead + AsyncWrite>(io: T) -> length_delimited::Framed<T> {
-    length_delimited::Framed::new(io) // by default a big endian u32 at the start
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct TcpConfig {
+    pub heartbeat: HeartbeatConfig,
+    pub reconnect: ReconnectPolicy, // clients only
+    pub send_queue: SendQueueConfig, // per connection
+    pub limits: ConnectionLimits, // what we'll accept from the other end of each connection
+}
+
+pub fn bind_transport<T: AsyncRead + AsyncWrite>(io: T, max_frame_length: usize) -> length_delimited::Framed<T> {
+    length_delimited::Builder::new().max_frame_length(max_frame_length).new_framed(io) // by default a big endian u32 at the start
 }
 
 pub struct PoisonPill {
@@ -36,7 +65,70 @@ pub struct PoisonPill {
 
 impl PoisonPill {
     pub fn shutdown(self) -> std::result::Result<u32, std::boxed::Box<std::any::Any + std::marker::Send>> {
-        self.sender.send(99).unwrap();
+        let _ = self.sender.send(99); // the thread may have stopped on its own already
         self.join_handle.join()
     }
-}
\ No newline at end of file
+}
+
+// why a network thread's loop stopped
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shutdown {
+    Poisoned, // PoisonPill::shutdown
+    PillDropped, // the PoisonPill went away without a shutdown
+    HandlerGone, // the event receiver was dropped, there's nobody to tell anything to
+}
+
+// the events a network thread sends to its game loop, once that's gone the network loop shuts down
+pub struct EventRelay<E> {
+    sender: Sender<E>,
+    gone: Rc<RefCell<Option<oneshot::Sender<()>>>>,
+}
+
+impl<E> Clone for EventRelay<E> {
+    fn clone(&self) -> EventRelay<E> {
+        EventRelay {
+            sender: self.sender.clone(),
+            gone: self.gone.clone(),
+        }
+    }
+}
+
+impl<E> EventRelay<E> {
+    pub fn new(sender: Sender<E>) -> (EventRelay<E>, oneshot::Receiver<()>) {
+        let (gone_sender, gone_receiver) = oneshot::channel();
+        let relay = EventRelay {
+            sender,
+            gone: Rc::new(RefCell::new(Some(gone_sender))),
+        };
+        (relay, gone_receiver)
+    }
+
+    // false if the receiver is gone
+    pub fn send(&self, event: E) -> bool {
+        match self.sender.send(event) {
+            Ok(()) => true,
+            Err(_) => {
+                if let Some(gone) = self.gone.borrow_mut().take() {
+                    let _ = gone.send(());
+                }
+                false
+            },
+        }
+    }
+}
+
+// what a network thread's core runs until
+pub fn until_shutdown(poison_receiver: oneshot::Receiver<u32>, gone_receiver: oneshot::Receiver<()>) -> Box<dyn Future<Item=Shutdown, Error=()>> {
+    let poisoned = poison_receiver.then(|r| Ok::<Shutdown, ()>(if r.is_ok() { Shutdown::Poisoned } else { Shutdown::PillDropped }));
+    // every relay being dropped isn't the handler going away
+    let gone = gone_receiver.then(|r| match r {
+        Ok(()) => future::Either::A(future::ok::<Shutdown, ()>(Shutdown::HandlerGone)),
+        Err(_) => future::Either::B(future::empty()),
+    });
+    Box::new(poisoned.select(gone).map(|(shutdown, _)| shutdown).map_err(|_| ()))
+}
+
+// network threads report whether they started (bound, have a core ...) before the run_ functions return
+pub fn await_start(started: Receiver<PuckNetworkResult<()>>) -> PuckNetworkResult<()> {
+    started.recv().unwrap_or(Err(PuckNetworkError::ThreadStopped))
+}
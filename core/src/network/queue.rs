@@ -0,0 +1,138 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest, // make room by discarding the longest waiting message
+    DropNewest, // discard the message that didn't fit
+    Disconnect, // the peer can't keep up, drop the connection
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SendQueueConfig {
+    pub capacity: usize, // messages waiting on the socket, per connection
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> SendQueueConfig {
+        SendQueueConfig {
+            capacity: 1024,
+            overflow: OverflowPolicy::Disconnect,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueCounters {
+    depth: AtomicUsize,
+    peak: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// a connection's send queue as seen from any thread, updated by the network thread
+#[derive(Debug, Clone, Default)]
+pub struct QueueMetrics {
+    counters: Arc<QueueCounters>,
+}
+
+impl QueueMetrics {
+    pub fn new() -> QueueMetrics {
+        QueueMetrics::default()
+    }
+
+    // messages waiting to be written right now
+    pub fn depth(&self) -> usize {
+        self.counters.depth.load(Ordering::Relaxed)
+    }
+
+    // the deepest the queue has been
+    pub fn peak(&self) -> usize {
+        self.counters.peak.load(Ordering::Relaxed)
+    }
+
+    // messages discarded by the overflow policy
+    pub fn dropped(&self) -> usize {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn record_depth(&self, depth: usize) {
+        self.counters.depth.store(depth, Ordering::Relaxed);
+        if depth > self.peak() {
+            self.counters.peak.store(depth, Ordering::Relaxed);
+        }
+    }
+
+    fn record_dropped(&self) {
+        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// what happened to a pushed message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    Dropped, // it, or an older message, was discarded
+    Overflowed, // the policy is Disconnect and the queue was full
+}
+
+pub struct SendQueue<T> {
+    pub config: SendQueueConfig,
+    pub metrics: QueueMetrics,
+    queued: VecDeque<T>,
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(config: SendQueueConfig, metrics: QueueMetrics) -> SendQueue<T> {
+        SendQueue {
+            config,
+            metrics,
+            queued: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    pub fn push(&mut self, t: T) -> Pushed {
+        let pushed = if self.queued.len() < self.config.capacity {
+            self.queued.push_back(t);
+            Pushed::Queued
+        } else {
+            match self.config.overflow {
+                OverflowPolicy::DropOldest => {
+                    if self.queued.pop_front().is_some() {
+                        self.queued.push_back(t);
+                    }
+                    self.metrics.record_dropped();
+                    Pushed::Dropped
+                },
+                OverflowPolicy::DropNewest => {
+                    self.metrics.record_dropped();
+                    Pushed::Dropped
+                },
+                OverflowPolicy::Disconnect => Pushed::Overflowed,
+            }
+        };
+        self.metrics.record_depth(self.queued.len());
+        pushed
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let t = self.queued.pop_front();
+        self.metrics.record_depth(self.queued.len());
+        t
+    }
+
+    // for a message that was popped but couldn't be written yet
+    pub fn push_front(&mut self, t: T) {
+        self.queued.push_front(t);
+        self.metrics.record_depth(self.queued.len());
+    }
+}
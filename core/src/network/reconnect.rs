@@ -0,0 +1,138 @@
+use std::time::Duration;
+use std::collections::VecDeque;
+
+use futures::sync::mpsc::UnboundedSender;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration, // before the first retry, doubled for every retry after that
+    pub max_backoff: Duration,
+    pub max_attempts: Option<u32>, // retries in a row before giving up, None retries forever, Some(0) never retries
+    pub buffer: usize, // outbound messages held while disconnected, the oldest are dropped past this
+}
+
+impl ReconnectPolicy {
+    pub fn never() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            .. ReconnectPolicy::default()
+        }
+    }
+
+    // how long to wait before retry number attempt (from 1), None once we should give up
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || self.max_attempts.map(|max| attempt > max).unwrap_or(false) {
+            return None;
+        }
+        // past what a u32 or a Duration can hold we're well beyond max_backoff anyway
+        let backoff = 1u32.checked_shl(attempt - 1).and_then(|factor| self.initial_backoff.checked_mul(factor)).unwrap_or(self.max_backoff);
+        Some(::std::cmp::min(backoff, self.max_backoff))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(8),
+            max_attempts: Some(8),
+            buffer: 256,
+        }
+    }
+}
+
+// outbound messages for whichever connection is current, held (up to a bound) while there isn't one
+pub struct Outbox<E> {
+    pub bound: usize,
+    pub dropped: u64, // messages dropped to stay within the bound
+    pub closed: bool, // every sender is gone, nothing more will arrive
+    connection: Option<UnboundedSender<E>>,
+    buffered: VecDeque<E>,
+}
+
+impl<E> Outbox<E> {
+    pub fn new(bound: usize) -> Outbox<E> {
+        Outbox {
+            bound,
+            dropped: 0,
+            closed: false,
+            connection: None,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffered.len()
+    }
+
+    pub fn push(&mut self, e: E) {
+        let e = match self.connection {
+            Some(ref connection) => match connection.unbounded_send(e) {
+                Ok(()) => return,
+                Err(err) => err.into_inner(),
+            },
+            None => e,
+        };
+        self.connection = None;
+        if self.bound == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.buffered.len() >= self.bound {
+            self.buffered.pop_front();
+            self.dropped += 1;
+        }
+        self.buffered.push_back(e);
+    }
+
+    // everything held goes out first, in the order it was pushed
+    pub fn connect(&mut self, connection: UnboundedSender<E>) {
+        for e in self.buffered.drain(..) {
+            let _ = connection.unbounded_send(e);
+        }
+        self.connection = Some(connection);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+    }
+
+    // dropping the connection's sender ends its writer
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.connection = None;
+        self.buffered.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.backoff(0), None);
+        assert_eq!(policy.backoff(1), Some(Duration::from_millis(250)));
+        assert_eq!(policy.backoff(3), Some(Duration::from_secs(1)));
+        assert_eq!(policy.backoff(8), Some(Duration::from_secs(8)));
+        assert_eq!(policy.backoff(9), None);
+    }
+
+    #[test]
+    fn backoff_never_overflows() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_secs(u64::MAX / 4),
+            max_backoff: Duration::from_secs(u64::MAX),
+            max_attempts: None,
+            .. ReconnectPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Some(Duration::from_secs(u64::MAX / 4)));
+        assert_eq!(policy.backoff(8), Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(policy.backoff(u32::MAX), Some(Duration::from_secs(u64::MAX)));
+
+        let forever = ReconnectPolicy { max_attempts: None, .. ReconnectPolicy::default() };
+        assert_eq!(forever.backoff(40), Some(Duration::from_secs(8)));
+        assert_eq!(forever.backoff(u32::MAX), Some(Duration::from_secs(8)));
+    }
+}
@@ -5,7 +5,7 @@ use std::net::SocketAddr;
 
 use std;
 
-use super::{PuckNetworkResult, PoisonPill, bind_transport};
+use super::{PuckNetworkResult, PuckNetworkError, PoisonPill, EventRelay, bind_transport, until_shutdown, await_start};
 
 // use std::sync::mpsc::Sender;
 
@@ -15,10 +15,11 @@ use serde::de::{DeserializeOwned};
 use futures;
 use futures::sync::mpsc::{UnboundedSender};
 use futures::sync::oneshot;
-use futures::{Stream, Sink, Future};
+use futures::{future, Stream, Sink, Future};
 
 // use tokio_io::io;
 use std::fmt::Debug;
+use std::io;
 
 
 use tokio_core::net::{TcpListener};
@@ -26,9 +27,12 @@ use tokio_core::reactor::Core;
 
 use std::thread;
 
-use bytes::{BytesMut};
-
-use super::codec::AsymmetricCodec;
+use super::codec::{AsymmetricCodec, deserialize_bincode};
+use super::handshake::{Handshake, HandshakeReply, handshake_frame, handshake_within};
+use super::heartbeat::{DisconnectReason, Latency};
+use super::framing::drive_connection;
+use super::queue::QueueMetrics;
+use super::TcpConfig;
 
 
 // we could in theory hand one of these directly to the client ...
@@ -40,28 +44,31 @@ pub struct ServerEventHandler<SIE, SOE> { // this is a "logical" handle for the
 
 #[derive(Debug, Clone)]
 pub enum ServerInboundEvent<SIE, SOE> {
-    ClientConnected { address : SocketAddr, client_sender : UnboundedSender<SOE> },
+    ClientConnected { address : SocketAddr, client_sender : UnboundedSender<SOE>, queue: QueueMetrics }, // queue is how far behind writing to this client we are
     ClientMessage { address: SocketAddr, event: SIE },
-    ClientDisconnected { address : SocketAddr },
-    FailureToBind { address : SocketAddr }, // last 2 events could be combined in some form of "TCPServer finished with Result ...."
+    ClientDisconnected { address : SocketAddr, reason: DisconnectReason },
+    ClientLatency { address: SocketAddr, latency: Latency }, // after every answered heartbeat
+    ClientRejected { address: SocketAddr, handshake: Option<Handshake> }, // None when the first frame wasn't a handshake at all
+    FailureToBind { address : SocketAddr, error: io::ErrorKind }, // run_server also returns the error, the server never started
     ServerFinished { address: SocketAddr },
 }
 
 
-pub fn run_server<SIE, SOE, C>(server_handler:ServerEventHandler<SIE, SOE>, bind_address: SocketAddr) -> PuckNetworkResult<PoisonPill>
+pub fn run_server<SIE, SOE, C>(server_handler:ServerEventHandler<SIE, SOE>, bind_address: SocketAddr, schema_hash: u64, config: TcpConfig) -> PuckNetworkResult<PoisonPill>
     where SIE : DeserializeOwned + Send + Clone + Debug + 'static, SOE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<SIE, SOE> { // spawns a server and returns a poison pill handle ... that can be used to terminate the server
     let (poison_sender, poison_receiver) = oneshot::channel();
-
-
-    // what do we do if we can't bind :-/ ... send a failure to bind event
+    let (started_sender, started_receiver) = std::sync::mpsc::channel();
 
     let join_handle = thread::spawn(move || {
         println!("TCPServer :: starting");
-        create_server::<SIE, SOE, C>(server_handler, bind_address, poison_receiver);
+        create_server::<SIE, SOE, C>(server_handler, bind_address, schema_hash, config, started_sender, poison_receiver);
         println!("TCPServer :: finished");
         12
     });
 
+    // a bind failure comes back here as well as being sent as FailureToBind
+    await_start(started_receiver)?;
+
     Ok(PoisonPill {
         sender: poison_sender,
         join_handle: join_handle,
@@ -69,69 +76,101 @@ pub fn run_server<SIE, SOE, C>(server_handler:ServerEventHandler<SIE, SOE>, bind
 }
 
 
-pub fn create_server<SIE, SOE, C>(server_handler:ServerEventHandler<SIE, SOE>, bind_address: SocketAddr, poison_receiver: oneshot::Receiver<u32>)
+pub fn create_server<SIE, SOE, C>(server_handler:ServerEventHandler<SIE, SOE>, bind_address: SocketAddr, schema_hash: u64, config: TcpConfig, started: std::sync::mpsc::Sender<PuckNetworkResult<()>>, poison_receiver: oneshot::Receiver<u32>)
     where SIE : DeserializeOwned + 'static + Clone + Debug, SOE : Serialize + 'static + Clone + Debug, C: AsymmetricCodec<SIE, SOE> {
-    let mut core = Core::new().expect("TCPSERVER A NEW CORE"); // io result
+    let (events, gone_receiver) = EventRelay::new(server_handler.sender);
+
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
 
     let handle = core.handle();
+    let handshake = Handshake::new::<SIE, SOE, C>(schema_hash);
 
-    let socket = TcpListener::bind(&bind_address, &handle).expect("TCPSERVER BIND");
+    let socket = match TcpListener::bind(&bind_address, &handle) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("TCPServer :: couldnt bind {} -> {:?}", bind_address, e);
+            events.send(ServerInboundEvent::FailureToBind { address: bind_address, error: e.kind() });
+            let _ = started.send(Err(PuckNetworkError::Bind { address: bind_address, error: e }));
+            return;
+        },
+    };
+    let _ = started.send(Ok(()));
 
-    let server_handler_copy = server_handler.clone();
+    let finished_events = events.clone();
 
     let srv = socket.incoming().for_each(move |(socket, addr)| {
         println!("TCPServer :: got a connection to {:?}", addr);
 
-        let (client_send, client_receive) = futures::sync::mpsc::unbounded();
-        let other_handle = server_handler.clone();
-        let hhrrrm = server_handler.clone();
-
-        let (sink, stream) = bind_transport(socket).split();
-
-        // use the raw send
-        hhrrrm.sender.send(ServerInboundEvent::ClientConnected { address : addr, client_sender : client_send }).expect("TCPSERVER SEND CLIENTCONNECTED");
-
-        let socket_reader = stream.for_each(move |m| {
-            println!("TCPServer :: hey mang, I got a message -> {:?}", m);
-
-            match C::deserialize_incoming(&m) {
-                Ok(ie) => {
-                    println!("TCPServer :: received incoming message -> {:?}", ie);
-                    hhrrrm.sender.send(ServerInboundEvent::ClientMessage { address : addr, event : ie }).expect("TCPSERVER SEND CLIENTMESSAGE");
-                },
-                Err(e) => println!("TCPServer :: couldnt deserialize incoming message -> {:?}", e),
+        let other_handle = events.clone();
+        let hhrrrm = events.clone();
+        let timed_out_events = events.clone();
+        let connection_handle = handle.clone();
+
+        let (sink, stream) = bind_transport(socket, config.limits.max_frame_length).split();
+
+        // nothing but the handshake is read until the client's been accepted
+        let handshaken = handshake_within(stream.into_future().map_err(|(e, _)| e).and_then(move |(first, stream)| {
+            let theirs = first.and_then(|bytes| deserialize_bincode::<Handshake>(&bytes).ok());
+            let reply = match theirs {
+                Some(ref h) if *h == handshake => HandshakeReply::Accepted,
+                _ => HandshakeReply::Rejected { expected: handshake },
+            };
+            future::result(handshake_frame(&reply)).and_then(move |reply_bytes| {
+                sink.send(reply_bytes).map(move |sink| (sink, stream, theirs, reply))
+            })
+        }), config.heartbeat.timeout, &handle).map_err(move |e| {
+            println!("TCPServer :: handshake with {:?} failed -> {:?}", addr, e);
+            if e.kind() == io::ErrorKind::TimedOut {
+                timed_out_events.send(ServerInboundEvent::ClientDisconnected { address: addr, reason: DisconnectReason::TimedOut });
             }
-
-            Ok(())
         });
 
-        let socket_writer = client_receive.fold(sink, |sink, msg| {
-            println!("TCPServer :: writing an outbound event to the client -> {:?}", msg);
-            let mut some_bytes : BytesMut = BytesMut::new();
-            match C::serialize_outgoing(&msg, &mut some_bytes) {
-                Ok(()) => (),
-                Err(e) => println!("TCPServer :: couldnt serialize event -> {:?}", e),
+        let connection = handshaken.and_then(move |(sink, stream, theirs, reply)| {
+            if reply != HandshakeReply::Accepted {
+                println!("TCPServer :: rejecting {:?} with handshake {:?}", addr, theirs);
+                hhrrrm.send(ServerInboundEvent::ClientRejected { address: addr, handshake: theirs });
+                return Ok(());
             }
-            let amt = sink.send(some_bytes); // should only do this on happy path
-            amt.map_err(|_| ())
-        });
 
-        let socket_reader = socket_reader.map_err(|_| ());
-        let connection = socket_reader.map(|_| ()).select(socket_writer.map(|_| ()));
-        handle.spawn(connection.then(move |_| {
-            println!("TCPServer :: Connection {} closed.", addr);
-            &other_handle.sender.send(ServerInboundEvent::ClientDisconnected { address : addr }).expect("TCPSERVER SEND CLIENTDISCONNECT");
+            let (client_send, client_receive) = futures::sync::mpsc::unbounded();
+            let queue = QueueMetrics::new();
+
+            // use the raw send
+            hhrrrm.send(ServerInboundEvent::ClientConnected { address : addr, client_sender : client_send, queue: queue.clone() });
+
+            let latency_handle = hhrrrm.clone();
+            let connection = drive_connection::<_, _, SIE, SOE, C, _, _>((sink, stream), client_receive, config, queue, &connection_handle, move |ie| {
+                hhrrrm.send(ServerInboundEvent::ClientMessage { address : addr, event : ie });
+            }, move |latency| {
+                latency_handle.send(ServerInboundEvent::ClientLatency { address: addr, latency });
+            });
+            connection_handle.spawn(connection.then(move |reason| {
+                let reason = reason.unwrap_or(DisconnectReason::Dropped);
+                println!("TCPServer :: Connection {} closed -> {:?}", addr, reason);
+                other_handle.send(ServerInboundEvent::ClientDisconnected { address : addr, reason });
+                Ok(())
+            }));
+
             Ok(())
-        }));
+        });
+        handle.spawn(connection);
 
         Ok(())
     });
 
-    let without_error = srv.map_err(|_| () );
+    let without_error = srv.map_err(|e| println!("TCPServer :: stopped accepting connections -> {:?}", e));
 
     core.handle().spawn(without_error);
 
-    core.run(poison_receiver).expect("TCPSERVER RUN");
+    if let Ok(shutdown) = core.run(until_shutdown(poison_receiver, gone_receiver)) {
+        println!("TCPServer :: shutting down -> {:?}", shutdown);
+    }
 
-    server_handler_copy.sender.send(ServerInboundEvent::ServerFinished { address : bind_address }).expect("TCPSERVER SEND SERVERFINISHED");
-}
\ No newline at end of file
+    finished_events.send(ServerInboundEvent::ServerFinished { address : bind_address });
+}
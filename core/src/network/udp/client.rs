@@ -0,0 +1,215 @@
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+use std::thread;
+use std::io;
+use std::fmt::Debug;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures;
+use rand;
+use futures::sync::oneshot;
+use futures::{Future, Stream, Sink};
+
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Core, Interval};
+
+use bytes::BytesMut;
+
+use network::{PuckNetworkResult, PuckNetworkError, PoisonPill, EventRelay, until_shutdown, await_start};
+use network::codec::AsymmetricCodec;
+use network::client::{ClientEventHandler, ClientInboundEvent, ChannelToServer};
+use network::handshake::Handshake;
+use network::heartbeat::DisconnectReason;
+use super::{UdpConfig, PacketCodec, Connection, Packet};
+
+enum ClientState {
+    Connecting { since: Instant, last_attempt: Option<Instant> },
+    Connected(Box<Connection>),
+    Gone, // failed to connect or disconnected, we don't try again
+}
+
+pub fn run_udp_client<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address: SocketAddr, schema_hash: u64, config: UdpConfig<COE>) -> PuckNetworkResult<PoisonPill>
+    where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
+    let (poison_sender, poison_receiver) = oneshot::channel();
+
+    let (started_sender, started_receiver) = ::std::sync::mpsc::channel();
+
+    let join_handle = thread::spawn(move || {
+        println!("UDPClient :: starting");
+        connect_udp_client_to::<CIE, COE, C>(client_handler, server_address, schema_hash, config, started_sender, poison_receiver);
+        println!("UDPClient :: finished");
+        12
+    });
+
+    await_start(started_receiver)?;
+
+    Ok(PoisonPill {
+        sender: poison_sender,
+        join_handle,
+    })
+}
+
+fn connect_udp_client_to<CIE, COE, C>(client_handler: ClientEventHandler<CIE, COE>, server_address: SocketAddr, schema_hash: u64, config: UdpConfig<COE>, started: ::std::sync::mpsc::Sender<PuckNetworkResult<()>>, poison_receiver: oneshot::Receiver<u32>)
+    where CIE : DeserializeOwned + Send + Clone + Debug + 'static, COE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<CIE, COE> {
+    let (events, gone_receiver) = EventRelay::new(client_handler.sender);
+
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
+    let handle = core.handle();
+    let handshake = Handshake::new::<CIE, COE, C>(schema_hash);
+
+    let local_address = if server_address.is_ipv4() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+    } else {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0)
+    };
+    let socket = match UdpSocket::bind(&local_address, &handle) {
+        Ok(socket) => socket,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::Bind { address: local_address, error: e }));
+            return;
+        },
+    };
+    let interval = match Interval::new(config.send_interval, &handle) {
+        Ok(interval) => interval,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
+    let _ = started.send(Ok(()));
+    let (sink, stream) = socket.framed(PacketCodec { conditions: config.conditions.clone() }).split();
+
+    let (packet_send, packet_receive) = futures::sync::mpsc::unbounded::<(SocketAddr, Packet)>();
+    let socket_writer = sink.send_all(packet_receive.map_err(|_| io::Error::other("UDPCLIENT PACKET CHANNEL CLOSED")));
+    handle.spawn(socket_writer.map(|_| ()).map_err(|e| println!("UDPClient :: writer finished -> {:?}", e)));
+
+    let state = Rc::new(RefCell::new(ClientState::Connecting { since: Instant::now(), last_attempt: None }));
+    let session = rand::random::<u64>(); // tells the server this is a new connection even if our address is reused
+
+    let channel_for = config.channel_for;
+    let reader_state = state.clone();
+    let reader_events = events.clone();
+    let reader_handle = handle.clone();
+    let socket_reader = stream.for_each(move |(address, packet)| {
+        if address != server_address {
+            return Ok(());
+        }
+        let now = Instant::now();
+        match packet {
+            Some(Packet::Accept) => {
+                let connecting = matches!(*reader_state.borrow(), ClientState::Connecting { .. });
+                if connecting {
+                    let connection = Connection::new(server_address, session, now);
+                    let queue = connection.queue.clone();
+                    *reader_state.borrow_mut() = ClientState::Connected(Box::new(connection));
+
+                    let (to_server_tx, to_server_rx) = futures::sync::mpsc::unbounded::<COE>();
+                    let writer_state = reader_state.clone();
+                    reader_handle.spawn(to_server_rx.for_each(move |msg| {
+                        let mut some_bytes : BytesMut = BytesMut::new();
+                        match C::serialize_outgoing(&msg, &mut some_bytes) {
+                            Ok(()) => {
+                                if let ClientState::Connected(ref mut connection) = *writer_state.borrow_mut() {
+                                    if let Err(e) = connection.queue(channel_for(&msg), some_bytes.to_vec()) {
+                                        println!("UDPClient :: couldnt send event -> {:?}", e);
+                                    }
+                                }
+                            },
+                            Err(e) => println!("UDPClient :: couldnt serialize event -> {:?}", e),
+                        }
+                        Ok(())
+                    }));
+
+                    let channel_to_server = ChannelToServer { sender: to_server_tx, queue };
+                    reader_events.send(ClientInboundEvent::ServerConnected { address: server_address, channel_to_server });
+                }
+            },
+            Some(Packet::Payload { seq, ack, ack_bits, frames }) => {
+                let delivered = match *reader_state.borrow_mut() {
+                    ClientState::Connected(ref mut connection) => connection.receive(seq, ack, ack_bits, frames, now),
+                    _ => Vec::new(),
+                };
+                for bytes in delivered {
+                    match C::deserialize_incoming(&bytes) {
+                        Ok(ie) => { reader_events.send(ClientInboundEvent::ServerMessage { address: server_address, event: ie }); },
+                        Err(e) => println!("UDPClient :: couldnt deser incoming event -> {:?}", e),
+                    }
+                }
+            },
+            Some(Packet::Reject { expected }) => {
+                let connecting = matches!(*reader_state.borrow(), ClientState::Connecting { .. });
+                if connecting {
+                    *reader_state.borrow_mut() = ClientState::Gone;
+                    println!("UDPClient :: handshake rejected by {}, they expected {:?}", server_address, expected);
+                    reader_events.send(ClientInboundEvent::HandshakeRejected { address: server_address, local: handshake, remote: Some(expected) });
+                }
+            },
+            Some(Packet::Disconnect) => {
+                let connected = matches!(*reader_state.borrow(), ClientState::Connected(_));
+                if connected {
+                    *reader_state.borrow_mut() = ClientState::Gone;
+                    println!("UDPClient :: Connection {} closed by server.", server_address);
+                    reader_events.send(ClientInboundEvent::ServerDisconnected { address: server_address, reason: DisconnectReason::Closed });
+                }
+            },
+            Some(Packet::Connect(_, _)) | None => (),
+        }
+        Ok(())
+    });
+    handle.spawn(socket_reader.map_err(|e| println!("UDPClient :: reader finished -> {:?}", e)));
+
+    let flush_events = events.clone();
+    let flush = interval.for_each(move |_| {
+        let now = Instant::now();
+        let mut state = state.borrow_mut();
+        let next = match *state {
+            ClientState::Connecting { since, ref mut last_attempt } => {
+                if now.duration_since(since) >= config.timeout {
+                    println!("UDPClient :: no answer from {}", server_address);
+                    flush_events.send(ClientInboundEvent::FailedToConnect { address: server_address });
+                    Some(ClientState::Gone)
+                } else {
+                    if last_attempt.map(|at| now.duration_since(at) >= config.resend_after).unwrap_or(true) {
+                        *last_attempt = Some(now);
+                        let _ = packet_send.unbounded_send((server_address, Packet::Connect(handshake, session)));
+                    }
+                    None
+                }
+            },
+            ClientState::Connected(ref mut connection) => {
+                if now.duration_since(connection.last_received) >= config.timeout {
+                    println!("UDPClient :: Connection {} timed out.", server_address);
+                    flush_events.send(ClientInboundEvent::ServerDisconnected { address: server_address, reason: DisconnectReason::TimedOut });
+                    Some(ClientState::Gone)
+                } else {
+                    for packet in connection.flush(now, config.resend_after, config.keepalive) {
+                        let _ = packet_send.unbounded_send((server_address, packet));
+                    }
+                    None
+                }
+            },
+            ClientState::Gone => None,
+        };
+        if let Some(next) = next {
+            *state = next;
+        }
+        Ok(())
+    });
+    handle.spawn(flush.map_err(|e| println!("UDPClient :: flush finished -> {:?}", e)));
+
+    if let Ok(shutdown) = core.run(until_shutdown(poison_receiver, gone_receiver)) {
+        println!("UDPClient :: shutting down -> {:?}", shutdown);
+    }
+
+    events.send(ClientInboundEvent::ClientFinished { address: server_address });
+}
@@ -0,0 +1,423 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeSet, VecDeque};
+
+use bincode;
+
+use TreeMap;
+use network::handshake::Handshake;
+use network::queue::QueueMetrics;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    ReliableOrdered, // resent until acked, delivered in the order sent
+    ReliableUnordered, // resent until acked, delivered as soon as it arrives
+    UnreliableSequenced, // sent once, anything older than the newest delivered is dropped
+}
+
+impl Channel {
+    fn index(&self) -> usize {
+        match *self {
+            Channel::ReliableOrdered => 0,
+            Channel::ReliableUnordered => 1,
+            Channel::UnreliableSequenced => 2,
+        }
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        *self != Channel::UnreliableSequenced
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub channel: Channel,
+    pub id: u64, // per channel
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Packet {
+    Connect(Handshake, u64), // with the client's session, repeated by the client until accepted or rejected
+    Accept,
+    Reject { expected: Handshake },
+    Disconnect,
+    Payload { seq: u64, ack: Option<u64>, ack_bits: u32, frames: Vec<Frame> }, // ack_bits bit n acks ack - 1 - n, no ack until we've received something
+}
+
+pub const MAX_PAYLOAD_SIZE : usize = 1200; // encoded bytes per payload packet, header and frame overhead included, frames are never split
+pub const RECEIVE_WINDOW : u64 = 1024; // reliable frame ids this far past the next one expected are dropped rather than buffered
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub size: usize, // of the packet carrying the frame alone
+}
+
+// the most message bytes a single frame can carry
+pub fn max_frame_bytes() -> usize {
+    MAX_PAYLOAD_SIZE - payload_header_size() - frame_size(&Frame { channel: Channel::ReliableOrdered, id: 0, bytes: Vec::new() })
+}
+
+// an empty payload packet with an ack, bincode's fixed size integers make this the same for any seq and acks
+fn payload_header_size() -> usize {
+    bincode::serialized_size(&Packet::Payload { seq: 0, ack: Some(0), ack_bits: 0, frames: Vec::new() }) as usize
+}
+
+fn frame_size(frame: &Frame) -> usize {
+    bincode::serialized_size(frame) as usize
+}
+
+// one side of an emulated connection, sequences packets, tracks acks and resends reliable frames
+pub struct Connection {
+    pub address: SocketAddr,
+    pub session: u64, // picked by the client, a connect from the same address with another session is a new connection
+    pub last_received: Instant,
+    pub last_sent: Instant,
+    pub queue: QueueMetrics, // reliable frames not yet acked plus unreliable ones waiting for a flush
+    local_seq: u64, // of the next packet we send
+    remote_seq: Option<u64>, // highest packet seq received
+    received_bits: u32,
+    ack_pending: bool, // received something since we last sent
+    next_ids: [u64; 3],
+    unreliable: VecDeque<Frame>, // queued until the next flush
+    unacked: TreeMap<(Channel, u64), (Frame, Option<Instant>)>, // reliable frames and when they were last sent
+    in_flight: TreeMap<u64, Vec<(Channel, u64)>>, // reliable frames carried by each unacked packet
+    ordered_next: u64,
+    ordered_buffer: TreeMap<u64, Vec<u8>>,
+    unordered_below: u64, // every unordered id below this has been delivered
+    unordered_seen: BTreeSet<u64>,
+    sequenced_last: Option<u64>,
+}
+
+impl Connection {
+    pub fn new(address: SocketAddr, session: u64, now: Instant) -> Connection {
+        Connection {
+            address,
+            session,
+            last_received: now,
+            last_sent: now,
+            queue: QueueMetrics::new(),
+            local_seq: 0,
+            remote_seq: None,
+            received_bits: 0,
+            ack_pending: false,
+            next_ids: [0; 3],
+            unreliable: VecDeque::new(),
+            unacked: TreeMap::new(),
+            in_flight: TreeMap::new(),
+            ordered_next: 0,
+            ordered_buffer: TreeMap::new(),
+            unordered_below: 0,
+            unordered_seen: BTreeSet::new(),
+            sequenced_last: None,
+        }
+    }
+
+    pub fn queue(&mut self, channel: Channel, bytes: Vec<u8>) -> Result<(), FrameTooLarge> {
+        let id = self.next_ids[channel.index()];
+        let frame = Frame { channel, id, bytes };
+        let size = payload_header_size() + frame_size(&frame);
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(FrameTooLarge { size });
+        }
+        self.next_ids[channel.index()] += 1;
+        if channel.is_reliable() {
+            self.unacked.insert((channel, id), (frame, None));
+        } else {
+            self.unreliable.push_back(frame);
+        }
+        self.record_depth();
+        Ok(())
+    }
+
+    // reliable frames sent but not yet acked
+    pub fn unacked_len(&self) -> usize {
+        self.unacked.len()
+    }
+
+    // returns the frames ready for delivery, in delivery order
+    pub fn receive(&mut self, seq: u64, ack: Option<u64>, ack_bits: u32, frames: Vec<Frame>, now: Instant) -> Vec<Vec<u8>> {
+        self.last_received = now;
+        self.ack_pending = true;
+        self.record_received(seq);
+
+        if let Some(ack) = ack {
+            self.acked(ack);
+            for n in 0..32u64 {
+                if ack_bits & (1 << n) != 0 && ack > n {
+                    self.acked(ack - 1 - n);
+                }
+            }
+        }
+
+        let mut delivered = Vec::new();
+        for frame in frames {
+            match frame.channel {
+                Channel::ReliableOrdered => {
+                    if frame.id >= self.ordered_next && frame.id - self.ordered_next < RECEIVE_WINDOW {
+                        self.ordered_buffer.insert(frame.id, frame.bytes);
+                    }
+                    while let Some(bytes) = self.ordered_buffer.remove(&self.ordered_next) {
+                        delivered.push(bytes);
+                        self.ordered_next += 1;
+                    }
+                },
+                Channel::ReliableUnordered => {
+                    if frame.id >= self.unordered_below && frame.id - self.unordered_below < RECEIVE_WINDOW && self.unordered_seen.insert(frame.id) {
+                        delivered.push(frame.bytes);
+                    }
+                    while self.unordered_seen.remove(&self.unordered_below) {
+                        self.unordered_below += 1;
+                    }
+                },
+                Channel::UnreliableSequenced => {
+                    if self.sequenced_last.map(|last| frame.id > last).unwrap_or(true) {
+                        self.sequenced_last = Some(frame.id);
+                        delivered.push(frame.bytes);
+                    }
+                },
+            }
+        }
+        self.record_depth();
+        delivered
+    }
+
+    // the packets to send now, reliable frames due a (re)send first then unreliable ones
+    // reliable frames past the receive window of the oldest unacked one on their channel wait, the other end would drop them
+    // an empty payload goes out to carry acks, or as a keepalive when nothing's been sent for a while
+    pub fn flush(&mut self, now: Instant, resend_after: Duration, keepalive: Duration) -> Vec<Packet> {
+        let mut oldest_unacked : [Option<u64>; 3] = [None; 3];
+        for &(channel, id) in self.unacked.keys() {
+            oldest_unacked[channel.index()] = oldest_unacked[channel.index()].or(Some(id));
+        }
+
+        let mut frames : Vec<Frame> = Vec::new();
+        for (&(channel, id), &mut (ref frame, ref mut sent_at)) in self.unacked.iter_mut() {
+            let in_window = oldest_unacked[channel.index()].map(|oldest| id - oldest < RECEIVE_WINDOW).unwrap_or(true);
+            let due = in_window && sent_at.map(|at| now.duration_since(at) >= resend_after).unwrap_or(true);
+            if due {
+                *sent_at = Some(now);
+                frames.push(frame.clone());
+            }
+        }
+        frames.extend(self.unreliable.drain(..));
+
+        let mut packets = Vec::new();
+        let mut current : Vec<Frame> = Vec::new();
+        let header = payload_header_size();
+        let mut size = header;
+        for frame in frames {
+            let frame_size = frame_size(&frame);
+            if !current.is_empty() && size + frame_size > MAX_PAYLOAD_SIZE {
+                let full = ::std::mem::take(&mut current);
+                packets.push(self.payload(full));
+                size = header;
+            }
+            size += frame_size;
+            current.push(frame);
+        }
+        if !current.is_empty() || self.ack_pending || now.duration_since(self.last_sent) >= keepalive {
+            packets.push(self.payload(current));
+        }
+
+        if !packets.is_empty() {
+            self.last_sent = now;
+            self.ack_pending = false;
+        }
+        self.record_depth();
+        packets
+    }
+
+    fn payload(&mut self, frames: Vec<Frame>) -> Packet {
+        let seq = self.local_seq;
+        self.local_seq += 1;
+
+        let reliable : Vec<(Channel, u64)> = frames.iter().filter(|f| f.channel.is_reliable()).map(|f| (f.channel, f.id)).collect();
+        if !reliable.is_empty() {
+            self.in_flight.insert(seq, reliable);
+        }
+        // packets this old were lost, their frames have been resent since
+        if seq >= 1024 {
+            self.in_flight = self.in_flight.split_off(&(seq - 1024));
+        }
+
+        Packet::Payload {
+            seq,
+            ack: self.remote_seq,
+            ack_bits: self.received_bits,
+            frames,
+        }
+    }
+
+    fn record_received(&mut self, seq: u64) {
+        match self.remote_seq {
+            None => {
+                self.remote_seq = Some(seq);
+                self.received_bits = 0;
+            },
+            Some(remote) if seq > remote => {
+                let shift = seq - remote;
+                self.received_bits = if shift > 32 {
+                    0
+                } else {
+                    (((self.received_bits as u64) << shift) | (1 << (shift - 1))) as u32
+                };
+                self.remote_seq = Some(seq);
+            },
+            Some(remote) if seq < remote => {
+                let behind = remote - seq;
+                if behind <= 32 {
+                    self.received_bits |= 1 << (behind - 1);
+                }
+            },
+            Some(_) => (),
+        }
+    }
+
+    fn record_depth(&self) {
+        self.queue.record_depth(self.unacked.len() + self.unreliable.len());
+    }
+
+    fn acked(&mut self, seq: u64) {
+        if let Some(carried) = self.in_flight.remove(&seq) {
+            for key in carried {
+                self.unacked.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(now: Instant) -> (Connection, Connection) {
+        (Connection::new("127.0.0.1:1".parse().unwrap(), 1, now), Connection::new("127.0.0.1:2".parse().unwrap(), 1, now))
+    }
+
+    // hands every packet to the other end, returning what it delivered
+    fn send(packets: Vec<Packet>, to: &mut Connection, now: Instant) -> Vec<Vec<u8>> {
+        let mut delivered = Vec::new();
+        for packet in packets {
+            if let Packet::Payload { seq, ack, ack_bits, frames } = packet {
+                delivered.extend(to.receive(seq, ack, ack_bits, frames, now));
+            }
+        }
+        delivered
+    }
+
+    fn frame_count(packets: &[Packet]) -> usize {
+        packets.iter().map(|p| match *p {
+            Packet::Payload { ref frames, .. } => frames.len(),
+            _ => 0,
+        }).sum()
+    }
+
+    const RESEND : Duration = Duration::from_millis(100);
+    const KEEPALIVE : Duration = Duration::from_secs(1);
+
+    #[test]
+    fn ordered_frames_wait_for_lost_ones_which_are_resent_until_acked() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        for n in 0..4u8 {
+            a.queue(Channel::ReliableOrdered, vec![n; 700]).unwrap(); // one frame per packet
+        }
+        let mut packets = a.flush(now, RESEND, KEEPALIVE);
+        assert_eq!(packets.len(), 4);
+
+        // the second packet is lost, the rest arrive backwards
+        packets.remove(1);
+        packets.reverse();
+        let delivered = send(packets, &mut b, now);
+        assert_eq!(delivered, vec![vec![0; 700]]);
+
+        send(b.flush(now, RESEND, KEEPALIVE), &mut a, now);
+        assert_eq!(a.unacked_len(), 1);
+        assert_eq!(frame_count(&a.flush(now, RESEND, KEEPALIVE)), 0); // not due a resend yet
+
+        let later = now + RESEND;
+        let resent = a.flush(later, RESEND, KEEPALIVE);
+        assert_eq!(frame_count(&resent), 1);
+        let delivered = send(resent, &mut b, later);
+        assert_eq!(delivered, vec![vec![1; 700], vec![2; 700], vec![3; 700]]);
+
+        send(b.flush(later, RESEND, KEEPALIVE), &mut a, later);
+        assert_eq!(a.unacked_len(), 0);
+        assert_eq!(frame_count(&a.flush(later + RESEND, RESEND, KEEPALIVE)), 0);
+    }
+
+    #[test]
+    fn unordered_frames_arrive_once_and_sequenced_ones_only_when_newer() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        a.queue(Channel::ReliableUnordered, vec![0; 700]).unwrap();
+        a.queue(Channel::ReliableUnordered, vec![1; 700]).unwrap();
+        let mut packets = a.flush(now, RESEND, KEEPALIVE);
+        packets.reverse();
+        let duplicate = packets.clone();
+        assert_eq!(send(packets, &mut b, now), vec![vec![1; 700], vec![0; 700]]);
+        assert!(send(duplicate, &mut b, now).is_empty());
+
+        a.queue(Channel::UnreliableSequenced, vec![0; 700]).unwrap();
+        a.queue(Channel::UnreliableSequenced, vec![1; 700]).unwrap();
+        let mut packets = a.flush(now, RESEND, KEEPALIVE);
+        packets.reverse();
+        assert_eq!(send(packets, &mut b, now), vec![vec![1; 700]]);
+        send(b.flush(now, RESEND, KEEPALIVE), &mut a, now);
+        assert_eq!(frame_count(&a.flush(now + RESEND, RESEND, KEEPALIVE)), 0); // never resent
+    }
+
+    #[test]
+    fn ids_outside_the_receive_window_are_dropped() {
+        let now = Instant::now();
+        let (_, mut b) = pair(now);
+        let far = vec![
+            Frame { channel: Channel::ReliableOrdered, id: RECEIVE_WINDOW, bytes: vec![1] },
+            Frame { channel: Channel::ReliableUnordered, id: RECEIVE_WINDOW, bytes: vec![2] },
+            Frame { channel: Channel::ReliableOrdered, id: RECEIVE_WINDOW - 1, bytes: vec![3] },
+        ];
+        assert!(b.receive(0, None, 0, far, now).is_empty());
+        assert_eq!(b.ordered_buffer.len(), 1);
+        assert!(b.unordered_seen.is_empty());
+    }
+
+    #[test]
+    fn senders_stay_inside_the_receive_window() {
+        let now = Instant::now();
+        let (mut a, _) = pair(now);
+        for _ in 0..(RECEIVE_WINDOW + 10) {
+            a.queue(Channel::ReliableOrdered, vec![0]).unwrap();
+        }
+        a.queue(Channel::ReliableUnordered, vec![0]).unwrap();
+        assert_eq!(frame_count(&a.flush(now, RESEND, KEEPALIVE)), RECEIVE_WINDOW as usize + 1);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let now = Instant::now();
+        let (mut a, _) = pair(now);
+        assert_eq!(a.queue(Channel::ReliableOrdered, vec![0; max_frame_bytes() + 1]), Err(FrameTooLarge { size: MAX_PAYLOAD_SIZE + 1 }));
+        assert_eq!(a.queue(Channel::ReliableOrdered, vec![0; max_frame_bytes()]), Ok(()));
+        assert_eq!(a.unacked_len(), 1);
+        assert!(bincode::serialize(&a.flush(now, RESEND, KEEPALIVE)[0], bincode::Infinite).unwrap().len() <= MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn many_small_frames_are_split_into_packets_that_fit() {
+        let now = Instant::now();
+        let (mut a, mut b) = pair(now);
+        b.receive(0, None, 0, Vec::new(), now); // so b's packets carry an ack, the largest header
+        for n in 0..300u32 {
+            a.queue(Channel::ReliableOrdered, vec![n as u8]).unwrap();
+            b.queue(Channel::UnreliableSequenced, vec![n as u8]).unwrap();
+        }
+        for packets in &[a.flush(now, RESEND, KEEPALIVE), b.flush(now, RESEND, KEEPALIVE)] {
+            assert!(packets.len() > 1);
+            assert_eq!(frame_count(packets), 300);
+            for packet in packets {
+                assert!(bincode::serialize(packet, bincode::Infinite).unwrap().len() <= MAX_PAYLOAD_SIZE);
+            }
+        }
+    }
+}
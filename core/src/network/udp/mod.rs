@@ -0,0 +1,94 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bincode;
+use tokio_core::net::UdpCodec;
+
+use super::conditioner::Conditions;
+
+pub mod connection;
+pub mod client;
+pub mod server;
+
+pub use self::connection::{Channel, Connection, Frame, Packet};
+
+// the same events as the tcp transport, but over udp with per message channels
+// good for state that's stale by the time a resend would arrive
+pub struct UdpConfig<E> {
+    pub channel_for: fn(&E) -> Channel, // which channel each outgoing message is sent on
+    pub send_interval: Duration, // how often queued frames are flushed into packets
+    pub resend_after: Duration, // unacked reliable frames (and unanswered connects) are resent after this
+    pub keepalive: Duration, // an empty packet goes out when nothing else has for this long
+    pub timeout: Duration, // a connection that hasn't heard anything for this long is dropped
+    pub conditions: Option<Conditions>, // loses datagrams both ways at the conditions' loss rate, for testing
+}
+
+impl<E> UdpConfig<E> {
+    pub fn new(channel_for: fn(&E) -> Channel) -> UdpConfig<E> {
+        UdpConfig {
+            channel_for,
+            send_interval: Duration::from_millis(10),
+            resend_after: Duration::from_millis(100),
+            keepalive: Duration::from_millis(250),
+            timeout: Duration::from_secs(5),
+            conditions: None,
+        }
+    }
+}
+
+impl<E> Default for UdpConfig<E> {
+    fn default() -> UdpConfig<E> {
+        UdpConfig::new(reliable_ordered::<E>)
+    }
+}
+
+impl<E> Clone for UdpConfig<E> {
+    fn clone(&self) -> UdpConfig<E> {
+        UdpConfig {
+            channel_for: self.channel_for,
+            send_interval: self.send_interval,
+            resend_after: self.resend_after,
+            keepalive: self.keepalive,
+            timeout: self.timeout,
+            conditions: self.conditions.clone(),
+        }
+    }
+}
+
+pub fn reliable_ordered<E>(_: &E) -> Channel {
+    Channel::ReliableOrdered
+}
+
+pub struct PacketCodec {
+    pub conditions: Option<Conditions>,
+}
+
+impl PacketCodec {
+    fn lose(&self) -> bool {
+        self.conditions.as_ref().map(|c| c.lose()).unwrap_or(false)
+    }
+}
+
+impl UdpCodec for PacketCodec {
+    type In = (SocketAddr, Option<Packet>); // None for datagrams that aren't ours, rather than ending the stream
+    type Out = (SocketAddr, Packet);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        if self.lose() {
+            return Ok((*src, None));
+        }
+        Ok((*src, bincode::deserialize(buf).ok()))
+    }
+
+    fn encode(&mut self, (address, packet): Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        if self.lose() {
+            return address; // an empty datagram, which the other end ignores
+        }
+        match bincode::serialize(&packet, bincode::Infinite) {
+            Ok(bytes) => buf.extend(bytes),
+            Err(e) => println!("UDP :: couldnt serialize packet -> {:?}", e),
+        }
+        address
+    }
+}
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+use std::thread;
+use std::io;
+use std::fmt::Debug;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use futures;
+use futures::sync::oneshot;
+use futures::{Future, Stream, Sink};
+
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Core, Interval};
+
+use bytes::BytesMut;
+
+use HashMap;
+use network::{PuckNetworkResult, PuckNetworkError, PoisonPill, EventRelay, until_shutdown, await_start};
+use network::codec::AsymmetricCodec;
+use network::server::{ServerEventHandler, ServerInboundEvent};
+use network::handshake::Handshake;
+use network::heartbeat::DisconnectReason;
+use super::{UdpConfig, PacketCodec, Connection, Packet};
+
+pub fn run_udp_server<SIE, SOE, C>(server_handler: ServerEventHandler<SIE, SOE>, bind_address: SocketAddr, schema_hash: u64, config: UdpConfig<SOE>) -> PuckNetworkResult<PoisonPill>
+    where SIE : DeserializeOwned + Send + Clone + Debug + 'static, SOE : Serialize + Send + Clone + Debug + 'static, C: AsymmetricCodec<SIE, SOE> {
+    let (poison_sender, poison_receiver) = oneshot::channel();
+
+    let (started_sender, started_receiver) = ::std::sync::mpsc::channel();
+
+    let join_handle = thread::spawn(move || {
+        println!("UDPServer :: starting");
+        create_udp_server::<SIE, SOE, C>(server_handler, bind_address, schema_hash, config, started_sender, poison_receiver);
+        println!("UDPServer :: finished");
+        12
+    });
+
+    await_start(started_receiver)?;
+
+    Ok(PoisonPill {
+        sender: poison_sender,
+        join_handle,
+    })
+}
+
+pub fn create_udp_server<SIE, SOE, C>(server_handler: ServerEventHandler<SIE, SOE>, bind_address: SocketAddr, schema_hash: u64, config: UdpConfig<SOE>, started: ::std::sync::mpsc::Sender<PuckNetworkResult<()>>, poison_receiver: oneshot::Receiver<u32>)
+    where SIE : DeserializeOwned + 'static + Clone + Debug, SOE : Serialize + 'static + Clone + Debug, C: AsymmetricCodec<SIE, SOE> {
+    let (events, gone_receiver) = EventRelay::new(server_handler.sender);
+
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
+    let handle = core.handle();
+    let handshake = Handshake::new::<SIE, SOE, C>(schema_hash);
+
+    let socket = match UdpSocket::bind(&bind_address, &handle) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("UDPServer :: couldnt bind {} -> {:?}", bind_address, e);
+            events.send(ServerInboundEvent::FailureToBind { address: bind_address, error: e.kind() });
+            let _ = started.send(Err(PuckNetworkError::Bind { address: bind_address, error: e }));
+            return;
+        },
+    };
+    let interval = match Interval::new(config.send_interval, &handle) {
+        Ok(interval) => interval,
+        Err(e) => {
+            let _ = started.send(Err(PuckNetworkError::IO(e)));
+            return;
+        },
+    };
+    let _ = started.send(Ok(()));
+    let (sink, stream) = socket.framed(PacketCodec { conditions: config.conditions.clone() }).split();
+
+    // everything we send goes through here, so both the reader and the flush timer can send
+    let (packet_send, packet_receive) = futures::sync::mpsc::unbounded::<(SocketAddr, Packet)>();
+    let socket_writer = sink.send_all(packet_receive.map_err(|_| io::Error::other("UDPSERVER PACKET CHANNEL CLOSED")));
+    handle.spawn(socket_writer.map(|_| ()).map_err(|e| println!("UDPServer :: writer finished -> {:?}", e)));
+
+    let connections : Rc<RefCell<HashMap<SocketAddr, Connection>>> = Rc::new(RefCell::new(HashMap::default()));
+
+    let channel_for = config.channel_for;
+    let reader_connections = connections.clone();
+    let reader_events = events.clone();
+    let reader_packet_send = packet_send.clone();
+    let reader_handle = handle.clone();
+    let socket_reader = stream.for_each(move |(address, packet)| {
+        let now = Instant::now();
+        match packet {
+            Some(Packet::Connect(theirs, _)) if theirs != handshake => {
+                println!("UDPServer :: rejecting {:?} with handshake {:?}", address, theirs);
+                let _ = reader_packet_send.unbounded_send((address, Packet::Reject { expected: handshake }));
+                reader_events.send(ServerInboundEvent::ClientRejected { address, handshake: Some(theirs) });
+            },
+            Some(Packet::Connect(_, session)) => {
+                let current = reader_connections.borrow().get(&address).map(|c| c.session);
+                // a client that restarted on the same address, nothing of its old connection carries over
+                if current.map(|s| s != session).unwrap_or(false) {
+                    reader_connections.borrow_mut().remove(&address);
+                    println!("UDPServer :: Connection {} replaced by a new session.", address);
+                    reader_events.send(ServerInboundEvent::ClientDisconnected { address, reason: DisconnectReason::Closed });
+                }
+                if current != Some(session) {
+                    println!("UDPServer :: got a connection to {:?}", address);
+                    let connection = Connection::new(address, session, now);
+                    let queue = connection.queue.clone();
+                    reader_connections.borrow_mut().insert(address, connection);
+
+                    let (client_send, client_receive) = futures::sync::mpsc::unbounded::<SOE>();
+                    let writer_connections = reader_connections.clone();
+                    reader_handle.spawn(client_receive.for_each(move |msg| {
+                        let mut some_bytes : BytesMut = BytesMut::new();
+                        match C::serialize_outgoing(&msg, &mut some_bytes) {
+                            Ok(()) => {
+                                if let Some(connection) = writer_connections.borrow_mut().get_mut(&address) {
+                                    if connection.session == session {
+                                        if let Err(e) = connection.queue(channel_for(&msg), some_bytes.to_vec()) {
+                                            println!("UDPServer :: couldnt send event -> {:?}", e);
+                                        }
+                                    }
+                                }
+                            },
+                            Err(e) => println!("UDPServer :: couldnt serialize event -> {:?}", e),
+                        }
+                        Ok(())
+                    }));
+
+                    reader_events.send(ServerInboundEvent::ClientConnected { address, client_sender: client_send, queue });
+                }
+                // accepts can be lost too, answer every connect
+                let _ = reader_packet_send.unbounded_send((address, Packet::Accept));
+            },
+            Some(Packet::Payload { seq, ack, ack_bits, frames }) => {
+                let delivered = match reader_connections.borrow_mut().get_mut(&address) {
+                    Some(connection) => connection.receive(seq, ack, ack_bits, frames, now),
+                    None => Vec::new(),
+                };
+                for bytes in delivered {
+                    match C::deserialize_incoming(&bytes) {
+                        Ok(ie) => { reader_events.send(ServerInboundEvent::ClientMessage { address, event: ie }); },
+                        Err(e) => println!("UDPServer :: couldnt deserialize incoming message -> {:?}", e),
+                    }
+                }
+            },
+            Some(Packet::Disconnect) => {
+                if reader_connections.borrow_mut().remove(&address).is_some() {
+                    println!("UDPServer :: Connection {} closed.", address);
+                    reader_events.send(ServerInboundEvent::ClientDisconnected { address, reason: DisconnectReason::Closed });
+                }
+            },
+            Some(Packet::Accept) | Some(Packet::Reject { .. }) | None => (),
+        }
+        Ok(())
+    });
+    handle.spawn(socket_reader.map_err(|e| println!("UDPServer :: reader finished -> {:?}", e)));
+
+    let flush_events = events.clone();
+    let flush = interval.for_each(move |_| {
+        let now = Instant::now();
+        let mut connections = connections.borrow_mut();
+
+        let timed_out : Vec<SocketAddr> = connections.values().filter(|c| now.duration_since(c.last_received) >= config.timeout).map(|c| c.address).collect();
+        for address in timed_out {
+            connections.remove(&address);
+            println!("UDPServer :: Connection {} timed out.", address);
+            flush_events.send(ServerInboundEvent::ClientDisconnected { address, reason: DisconnectReason::TimedOut });
+        }
+
+        for (address, connection) in connections.iter_mut() {
+            for packet in connection.flush(now, config.resend_after, config.keepalive) {
+                let _ = packet_send.unbounded_send((*address, packet));
+            }
+        }
+        Ok(())
+    });
+    handle.spawn(flush.map_err(|e| println!("UDPServer :: flush finished -> {:?}", e)));
+
+    if let Ok(shutdown) = core.run(until_shutdown(poison_receiver, gone_receiver)) {
+        println!("UDPServer :: shutting down -> {:?}", shutdown);
+    }
+
+    events.send(ServerInboundEvent::ServerFinished { address: bind_address });
+}
@@ -0,0 +1,40 @@
+use rand::Rng;
+
+// splitmix64, small, fast and its entire state is a single serializable u64
+// the simulation owns one of these and hands deterministic forks of it to each sink
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SimRng {
+    pub state: u64,
+}
+
+impl SimRng {
+    pub fn seeded(seed: u64) -> SimRng {
+        SimRng {
+            state: seed,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // an independent stream derived from the current state, without advancing it
+    pub fn fork(&self, salt: u64) -> SimRng {
+        let mut mixer = SimRng::seeded(self.state ^ salt.wrapping_mul(0xD1B54A32D192ED03));
+        SimRng::seeded(mixer.next_u64())
+    }
+}
+
+impl Rng for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        (SimRng::next_u64(self) >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        SimRng::next_u64(self)
+    }
+}
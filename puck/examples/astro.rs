@@ -9,10 +9,11 @@ extern crate serde_derive;
 extern crate serde;
 
 use cgmath::{Zero, InnerSpace, vec3, vec2, Rad};
+use rand::Rng;
 use std::f64::consts::PI;
 
 use puck_core::{Vec2f, Vec3f, Vec3, Tick, HashMap, TreeMap, Color, clamp};
-use puck_core::app::{App, SimSettings, IdSeed};
+use puck_core::app::{App, SimSettings, IdLease, World, Bounds, Interpolate, interpolate_entities};
 use puck_core::event::*;
 
 use puck::app::{RenderedApp, RenderSettings};
@@ -34,21 +35,15 @@ pub enum Id {
     Shot(u64),
 }
 
-impl Id {
-    pub fn next(&self) -> Option<Id> {
-        match self {
-            &Id::Game => None,
-            &Id::Player => None,
-            &Id::Rock(id) => Some(Id::Rock(id + 1)),
-            &Id::Shot(id) => Some(Id::Shot(id + 1)),
-        }
-    }
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum IdKind {
+    Rock,
+    Shot,
 }
 
 pub type IdRange = (Bound<Id>, Bound<Id>);
 
-pub const ALL_ROCKS : IdRange = (Included(Id::Rock(0)), Included(Id::Rock(1000)));
-pub const ALL_SHOTS : IdRange = (Included(Id::Shot(0)), Included(Id::Shot(1000)));
+pub const ALL_ROCKS : IdRange = (Included(Id::Rock(0)), Included(Id::Rock(u64::MAX)));
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum ActorKind {
@@ -76,6 +71,23 @@ pub struct Actor {
     pub shooting: bool,
 }
 
+impl Interpolate for Entity {
+    fn interpolate(previous: &Entity, current: &Entity, alpha: f64) -> Entity {
+        match (previous, current) {
+            (Entity::Actor(before), Entity::Actor(after)) => {
+                let mut actor = *after;
+                // wrapping around the screen edge is a jump, not movement
+                if (after.pos - before.pos).magnitude() < 100.0 {
+                    actor.pos = Vec2f::interpolate(&before.pos, &after.pos, alpha);
+                }
+                actor.facing = f32::interpolate(&before.facing, &after.facing, alpha);
+                Entity::Actor(actor)
+            },
+            (_, current) => *current,
+        }
+    }
+}
+
 const PLAYER_LIFE: f32 = 1.0;
 const SHOT_LIFE: f32 = 2.0;
 const ROCK_LIFE: f32 = 1.0;
@@ -150,9 +162,9 @@ fn create_shot(pos: Vec2f, facing: f32, velocity: Vec2f) -> Actor {
     }
 }
 
-fn random_vec(max_magnitude: f32) -> Vec2f {
-    let angle = rand::random::<f32>() * 2.0 * std::f32::consts::PI;
-    let mag = rand::random::<f32>() * max_magnitude;
+fn random_vec<R : Rng>(rng: &mut R, max_magnitude: f32) -> Vec2f {
+    let angle = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
+    let mag = rng.gen::<f32>() * max_magnitude;
     vec_from_angle(angle) * (mag)
 }
 
@@ -165,15 +177,15 @@ fn vec_from_angle(angle: f32) -> Vec2f { // should probably fix this on the rend
     Vec2f::new(vx, vy)
 }
 
-fn create_rocks(num: u64, exclusion: Vec2f, min_radius: f32, max_radius: f32) -> Vec<(Id, Entity)> {
+fn create_rocks<R : Rng>(rng: &mut R, ids: &mut IdLease<IdKind>, num: u64, exclusion: Vec2f, min_radius: f32, max_radius: f32) -> Vec<(Id, Entity)> {
     assert!(max_radius > min_radius);
-    let new_rock = |n| {
+    let new_rock = |_| {
         let mut rock = create_rock();
-        let r_angle = rand::random::<f32>() * 2.0 * std::f32::consts::PI;
-        let r_distance = rand::random::<f32>() * (max_radius - min_radius) + min_radius;
+        let r_angle = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
+        let r_distance = rng.gen::<f32>() * (max_radius - min_radius) + min_radius;
         rock.pos = exclusion + vec_from_angle(r_angle) * r_distance;
-        rock.velocity = random_vec(MAX_ROCK_VEL);
-        (Id::Rock(n), Entity::Actor(rock))
+        rock.velocity = random_vec(rng, MAX_ROCK_VEL);
+        (ids.next::<AstroApp>(IdKind::Rock), Entity::Actor(rock))
     };
     (0..num).map(new_rock).collect()
 }
@@ -249,12 +261,25 @@ impl App for AstroApp {
     type Entity = Entity; // do we need Eq?
     type EntityEvent = EntityEvent;
     type RenderEvent = SoundEvent;
+    type IdKind = IdKind;
 
-    fn modify_id(id: &Self::Id, seed_id: IdSeed) -> Option<Self::Id> {
-        None
+    fn id_for(kind: Self::IdKind, n: u64) -> Self::Id {
+        match kind {
+            IdKind::Rock => Id::Rock(n),
+            IdKind::Shot => Id::Shot(n),
+        }
+    }
+
+    fn id_kind(id: &Self::Id) -> Option<(Self::IdKind, u64)> {
+        match *id {
+            Id::Game => None,
+            Id::Player => None,
+            Id::Rock(n) => Some((IdKind::Rock, n)),
+            Id::Shot(n) => Some((IdKind::Shot, n)),
+        }
     }
 
-    fn handle_entity_event(event:&Self::EntityEvent, id: &Self::Id, entity: &mut Self::Entity, sink: &mut Sink<Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>>) {
+    fn handle_entity_event(event:&Self::EntityEvent, _id: &Self::Id, entity: &mut Self::Entity, _sink: &mut Sink<Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>, Self::IdKind>) {
         use Entity::*;
         use EntityEvent::*;
 
@@ -280,7 +305,14 @@ impl App for AstroApp {
         }
     }
 
-    fn simulate(time:Tick, entities:&TreeMap<Self::Id, Self::Entity>, id: &Self::Id, entity: &Self::Entity, sink: &mut CombinedSink<Self::EntityEvent, Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>>)  {
+    fn locate(entity: &Self::Entity) -> Option<Bounds> {
+        match *entity {
+            Entity::Actor(ref actor) => Some(Bounds { centre: vec2(actor.pos.x as f64, actor.pos.y as f64), radius: (actor.bbox_size / 2.0) as f64 }),
+            _ => None,
+        }
+    }
+
+    fn simulate(time:Tick, entities:&World<Self::Id, Self::Entity>, _id: &Self::Id, entity: &Self::Entity, sink: &mut CombinedSink<Self::EntityEvent, Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>, Self::IdKind>)  {
         use puck_core::event::Event::*;
         use Entity::*;
         use EntityEvent::*;
@@ -291,7 +323,7 @@ impl App for AstroApp {
                     let rock_count = entities.range(ALL_ROCKS).count();
                     if rock_count == 0 {
                         sink.mine.push(IncreaseLevel);
-                        let spawn_rocks : Vec<_> = create_rocks(level + 6, player.pos, 100.0, 250.0).into_iter().map(|(id, entity)| {
+                        let spawn_rocks : Vec<_> = create_rocks(&mut sink.rng, &mut sink.ids, level + 6, player.pos, 100.0, 250.0).into_iter().map(|(id, entity)| {
                             Event::SpawnEvent(id, entity)
                         }).collect();
                         for ev in spawn_rocks {
@@ -311,7 +343,7 @@ impl App for AstroApp {
                             let shot_velocity = vec_from_angle(actor.facing) * SHOT_SPEED;
                             let shot = create_shot(actor.pos, actor.facing, shot_velocity);
                             sink.mine.push(SetLife(PLAYER_LIFE));
-                            let shot_id = entities.range(ALL_SHOTS).last().and_then(|(id,_)| id.next() ).unwrap_or(Id::Shot(0));
+                            let shot_id = sink.ids.next::<AstroApp>(IdKind::Shot);
                             sink.routed.push(SpawnEvent(shot_id, Entity::Actor(shot)));
                         } else {
                             sink.mine.push(update_life(actor, time.tick_duration as f32));
@@ -347,7 +379,7 @@ impl RenderedApp for AstroApp {
         render_state.push(event.clone());
     }
 
-    fn render(time: RenderTick, dimensions: &Dimensions, entities:&TreeMap<Self::Id, Self::Entity>, render_state: &mut Self::RenderState, renderer: &mut OpenGLRenderer) -> SoundRender {
+    fn render(time: RenderTick, dimensions: &Dimensions, previous:&TreeMap<Self::Id, Self::Entity>, entities:&TreeMap<Self::Id, Self::Entity>, render_state: &mut Self::RenderState, renderer: &mut OpenGLRenderer) -> SoundRender {
         use Entity::*;
         use ActorKind::*;
 
@@ -364,7 +396,9 @@ impl RenderedApp for AstroApp {
         let shot = atlas.at(1, 0);
         let player = atlas.at(2, 0);
 
-        for (id, e) in entities {
+        let blended = interpolate_entities(previous, entities, time.accu_alpha);
+
+        for e in blended.values() {
             match e {
                 &Game { level, score } => {
                     // render some ui stuff?
@@ -41,6 +41,8 @@ pub trait RenderedApp : App {
 
     fn handle_input(input:&Input, dimensions: &Dimensions, entities: &Map<Self::Id, Self::Entity>, sink: &mut Sink<Event<Self::Id, Self::Entity, Self::EntityEvent, Self::RenderEvent>>);
     fn handle_render_event(event: &Self::RenderEvent, render_state: &mut Self::RenderState);
-    fn render(time: RenderTick, dimensions: &Dimensions, entities:&Map<Self::Id, Self::Entity>, render_state: &mut Self::RenderState, renderer: &mut OpenGLRenderer) -> SoundRender;
+    // previous is the state before the last tick, blend towards entities by time.accu_alpha for smooth motion
+    // (puck_core::app::interpolate_entities does this for entities implementing Interpolate)
+    fn render(time: RenderTick, dimensions: &Dimensions, previous:&Map<Self::Id, Self::Entity>, entities:&Map<Self::Id, Self::Entity>, render_state: &mut Self::RenderState, renderer: &mut OpenGLRenderer) -> SoundRender;
 }
 
@@ -1,17 +1,21 @@
 use gfx;
 use time;
-use multimap::MultiMap;
 
 use std::collections::BTreeMap as TreeMap;
+use std::net::SocketAddr;
+use std::mem;
 
 use render::gfx::{Renderer, construct_opengl_renderer};
 
 use {PuckResult, FileResources, RenderTick};
-use puck_core::app::{IdSeed, SimSettings};
+use puck_core::app::{AppEvent, AppClient, SimSettings, Simulation, connect_app_client};
+use puck_core::app::messages::{AppClientMessage, AppServerMessage};
+use puck_core::network::TcpConfig;
+use puck_core::network::codec::AsymmetricCodec;
 use puck_core::event::*;
-use puck_core::Tick;
 use super::{RenderedApp, RenderSettings};
-use std::collections::Bound::Included;
+
+pub use puck_core::app::runner::NANOSECONDS_IN_A_SECOND;
 
 
 pub struct ReneredAppRunner<RA, R, C, F, D> where RA : RenderedApp,
@@ -25,31 +29,129 @@ pub struct ReneredAppRunner<RA, R, C, F, D> where RA : RenderedApp,
     render_state: RA::RenderState,
 }
 
-pub const NANOSECONDS_IN_A_SECOND : u64 = 1_000_000_000u64;
-
 pub fn run<RA>(file_resources:FileResources, sim_settings: SimSettings, render_settings:RenderSettings, render_state: RA::RenderState, initial_entities: TreeMap<RA::Id, RA::Entity>) -> PuckResult<()> where RA : RenderedApp {
-    let mut renderer = construct_opengl_renderer(file_resources, render_settings.dimensions, render_settings.vsync, &render_settings.title)?;
+    run_simulation::<RA>(file_resources, render_settings, render_state, Simulation::new(sim_settings, initial_entities))
+}
 
-    // start file watcher
-    // start sound worker
+// run an existing simulation, e.g. one restored with Simulation::from_snapshot
+pub fn run_simulation<RA>(file_resources:FileResources, render_settings:RenderSettings, render_state: RA::RenderState, simulation: Simulation<RA>) -> PuckResult<()> where RA : RenderedApp {
+    let per_tick_ns = simulation.per_tick_ns();
+    let local = LocalSimulation {
+        simulation,
+        last_time: time::precise_time_ns(),
+        simulation_accu_ns: 0,
+        per_tick_ns,
+        render_events: Vec::new(),
+    };
+    render_loop::<RA, _>(file_resources, render_settings, render_state, local)?;
+    Ok(())
+}
 
-    let mut id_seed : IdSeed = 0;
+// renders the state of a remote AppServer, input is sent to the server rather than simulated locally
+pub fn run_client<RA, C>(file_resources:FileResources, render_settings:RenderSettings, render_state: RA::RenderState, server_address: SocketAddr) -> PuckResult<()> where RA : RenderedApp, C : AsymmetricCodec<AppServerMessage<RA>, AppClientMessage<RA>> {
+    let client = connect_app_client::<RA, C>(server_address, TcpConfig::default())?;
+    let client = render_loop::<RA, _>(file_resources, render_settings, render_state, client)?;
+    client.shutdown();
+    Ok(())
+}
 
-    let mut rs = render_state;
+// where the entities a frame renders come from, a simulation stepped here or one running on a server
+trait FrameSource<RA> where RA : RenderedApp {
+    // called at the start of every frame, before input is handled
+    fn begin_frame(&mut self);
+    fn entities(&self) -> &TreeMap<RA::Id, RA::Entity>;
+    fn previous_entities(&self) -> &TreeMap<RA::Id, RA::Entity>;
+    // the frame's input, false once there's nothing more to render
+    fn advance(&mut self, input: Vec<AppEvent<RA>>) -> bool;
+    fn render_tick(&self) -> RenderTick;
+    fn take_render_events(&mut self) -> Vec<RA::RenderEvent>;
+}
+
+struct LocalSimulation<RA> where RA : RenderedApp {
+    simulation: Simulation<RA>,
+    last_time: u64,
+    simulation_accu_ns: u64,
+    per_tick_ns: u64,
+    render_events: Vec<RA::RenderEvent>,
+}
+
+impl<RA> FrameSource<RA> for LocalSimulation<RA> where RA : RenderedApp {
+    fn begin_frame(&mut self) {
+        let time = time::precise_time_ns();
+        self.simulation_accu_ns += time - self.last_time;
+        self.last_time = time;
+    }
+
+    fn entities(&self) -> &TreeMap<RA::Id, RA::Entity> {
+        &self.simulation.entities
+    }
+
+    fn previous_entities(&self) -> &TreeMap<RA::Id, RA::Entity> {
+        &self.simulation.last_entities
+    }
+
+    fn advance(&mut self, mut input: Vec<AppEvent<RA>>) -> bool {
+        self.simulation.push_all(&mut input);
+        while self.simulation_accu_ns > self.per_tick_ns {
+            self.render_events.append(&mut self.simulation.step());
+            self.simulation_accu_ns -= self.per_tick_ns;
+        }
+        self.simulation.running
+    }
+
+    fn render_tick(&self) -> RenderTick {
+        RenderTick {
+            n: self.simulation.tick,
+            accu_alpha: (self.simulation_accu_ns as f64) / (self.per_tick_ns as f64), // percentage of a frame that has accumulated
+            tick_rate: self.simulation.settings.tick_rate, // per second
+        }
+    }
+
+    fn take_render_events(&mut self) -> Vec<RA::RenderEvent> {
+        mem::take(&mut self.render_events)
+    }
+}
+
+impl<RA> FrameSource<RA> for AppClient<RA> where RA : RenderedApp {
+    fn begin_frame(&mut self) {
+        self.poll();
+    }
 
-    let start_time = time::precise_time_ns();
-    let mut last_time = start_time;
-    let mut simulation_accu_ns = 0_u64;
+    fn entities(&self) -> &TreeMap<RA::Id, RA::Entity> {
+        &self.entities
+    }
 
-    let mut last_entities : TreeMap<RA::Id, RA::Entity> = initial_entities.clone();
-    let mut entities : TreeMap<RA::Id, RA::Entity> = initial_entities.clone();
+    fn previous_entities(&self) -> &TreeMap<RA::Id, RA::Entity> {
+        &self.previous
+    }
 
-    let mut tick = 0_u64;
+    fn advance(&mut self, input: Vec<AppEvent<RA>>) -> bool {
+        self.send(input);
+        !self.finished
+    }
 
-    let per_tick_ns = NANOSECONDS_IN_A_SECOND / sim_settings.tick_rate;
+    fn render_tick(&self) -> RenderTick {
+        RenderTick {
+            n: self.tick,
+            accu_alpha: self.alpha(),
+            tick_rate: self.tick_rate.unwrap_or(0),
+        }
+    }
 
-    let mut to_route : Vec<_> = Vec::new();
-    let mut render_events : Vec<_> = Vec::new();
+    fn take_render_events(&mut self) -> Vec<RA::RenderEvent> {
+        AppClient::take_render_events(self)
+    }
+}
+
+// opens the window and renders frames from source until it finishes or the window is closed, handing the source back
+fn render_loop<RA, S>(file_resources:FileResources, render_settings:RenderSettings, render_state: RA::RenderState, source: S) -> PuckResult<S> where RA : RenderedApp, S : FrameSource<RA> {
+    let mut renderer = construct_opengl_renderer(file_resources, render_settings.dimensions, render_settings.vsync, &render_settings.title)?;
+
+    // start file watcher
+    // start sound worker
+
+    let mut rs = render_state;
+    let mut source = source;
 
     let mut running = true;
 
@@ -58,127 +160,28 @@ pub fn run<RA>(file_resources:FileResources, sim_settings: SimSettings, render_s
 
         let (dimensions, input) = renderer.begin_frame(false, false);
 
+        source.begin_frame();
 
-        let time = time::precise_time_ns();
-
-
-        let since_start_ns = time - start_time;
-        let time_delta_ns = time - last_time;
-
-        simulation_accu_ns += time_delta_ns;
         let mut sink = Sink::empty();
-        RA::handle_input(&input, &dimensions, &entities, &mut sink);
-        to_route.append(&mut sink.events);
-
-        // ROUTE THE EVENTS
-
-        while simulation_accu_ns > per_tick_ns {
-//            println!("tick @ {:?}", tick);
-            // simulate
-            let simulate_tick = Tick {
-                n: tick,
-                tick_duration: (per_tick_ns as f64) / (NANOSECONDS_IN_A_SECOND as f64),
-                tick_rate: sim_settings.tick_rate, // per second
-            };
-
-            last_entities = entities;
-
-            let mut entity_events = MultiMap::new();
-
-            for ev in to_route {
-//                println!("handle event -> {:?}", ev);
-                match ev  {
-                    Event::Shutdown => running = false,
-                    Event::SpawnEvent(id, entity) => {
-                        let use_id = match RA::modify_id(&id, id_seed) {
-                            Some(new_id) => {
-                                id_seed += 1; // seed was consumed to generate the id, increment it
-                                new_id
-                            },
-                            None => id,
-                        };
-                        last_entities.insert(use_id, entity);
-                    },
-                    Event::Delete(id) => {
-                        last_entities.remove(&id);
-                    },
-                    Event::DeleteRange(from, to) => {
-                        let mut to_delete = Vec::new();
-                        for (k, _) in last_entities.range((Included(&from), Included(&to))) {
-                            to_delete.push(k.clone());
-                        }
-                        for k in to_delete {
-                            last_entities.remove(&k);
-                        }
-                    },
-                    Event::EntityEvent(id, entity_event) => entity_events.insert(id, entity_event),
-                    Event::RenderEvent(render_event) => render_events.push(render_event),
-                }
-            }
-
-            to_route = Vec::new();
-
-            entities = last_entities.iter().map(|(id, e)| {
-                let mut entity = e.clone();
-
-                // handle events from last frame
-                if let Some(evs) = entity_events.get_vec(id) {
-                    for event in evs {
-                        let mut sink = Sink::empty();
-                        RA::handle_entity_event(event, id, &mut entity, &mut sink);
-                        to_route.append(&mut sink.events);
-                    }
-                }
-
-                // simulate entity
-//                println!("simulate -> {:?} {:?}", id, e);
-                let mut combined_sink = CombinedSink::empty();
-                RA::simulate(simulate_tick, &last_entities, id, &entity, &mut combined_sink);
-
-
-                to_route.append(&mut combined_sink.routed.events);
-
-                // handle self effects immediately
-                for event in &combined_sink.mine.events {
-                    let mut sink = Sink::empty();
-                    RA::handle_entity_event(event, id, &mut entity, &mut sink);
-                    to_route.append(&mut sink.events);
-                }
-
-                (id.clone(), entity)
-            }).collect();
-
-
-            tick += 1;
-            simulation_accu_ns -= per_tick_ns;
+        RA::handle_input(&input, &dimensions, source.entities(), &mut sink);
+        if !source.advance(sink.events) {
+            running = false;
         }
 
-        // render
-        let render_tick = RenderTick {
-            n: tick,
-            accu_alpha: (simulation_accu_ns as f64) / (per_tick_ns as f64), // percentage of a frame that has accumulated
-            tick_rate: sim_settings.tick_rate, // per second
-        };
-
-        for render_event in render_events {
+        for render_event in source.take_render_events() {
             RA::handle_render_event(&render_event, &mut rs);
         }
-        render_events = Vec::new();
-//        println!("render");
+
         let ok = renderer.load_resources(false, false);
         if !ok {
             println!("renderer is not ok");
         }
-        RA::render(render_tick, &dimensions, &entities, &mut rs, &mut renderer);
+        RA::render(source.render_tick(), &dimensions, source.previous_entities(), source.entities(), &mut rs, &mut renderer);
 
         if input.close {
             running = false;
         }
-
-        last_time = time;
     }
 
-
-
-    Ok(())
-}
\ No newline at end of file
+    Ok(source)
+}
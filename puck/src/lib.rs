@@ -38,6 +38,8 @@ pub use resources::*;
 use std::io;
 use std::path::PathBuf;
 
+use puck_core::network::PuckNetworkError;
+
 
 
 pub type PuckResult<T> = Result<T, PuckError>;
@@ -60,6 +62,7 @@ pub enum PuckError {
     NoFiles,
     MismatchingDimensions, // path buf, expectation
     RenderingPipelineIncomplete,
+    NetworkError(PuckNetworkError),
 }
 
 impl From<image::ImageError> for PuckError {
@@ -68,6 +71,12 @@ impl From<image::ImageError> for PuckError {
     }
 }
 
+impl From<PuckNetworkError> for PuckError {
+    fn from(err: PuckNetworkError) -> Self {
+        PuckError::NetworkError(err)
+    }
+}
+
 impl From<io::Error> for PuckError {
     fn from(val: io::Error) -> PuckError {
         PuckError::IO(val)